@@ -1,6 +1,3 @@
 #!/bin/sh
 set -e
-cargo run --release -- --hanping_words=hanping_words.txt --extra_entries=hanping_extra_entries.txt "$@"
-echo '{}' > /tmp/media
-cd /tmp
-zip hanping_deck.apkg collection.anki2 media
+cargo run --release -- --hanping_words=hanping_words.txt --extra_entries=hanping_extra_entries.txt --output=/tmp/hanping_deck.apkg "$@"
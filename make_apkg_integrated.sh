@@ -1,6 +1,3 @@
 #!/bin/sh
 set -e
-cargo run --release -- --integrated "$@"
-echo '{}' > /tmp/media
-cd /tmp
-zip integrated_chinese_deck.apkg collection.anki2 media
+cargo run --release -- --integrated --output=/tmp/integrated_chinese_deck.apkg "$@"
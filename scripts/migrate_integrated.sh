@@ -6,29 +6,31 @@ set -e
   ./make_apkg_integrated.sh --traditional
 )
 
-./migrate_progress.py \
+cargo run --release -- migrate \
+  --match=identity \
+  --template_map 0=Simplified \
+  --template_map 0=Traditional \
+  --template_map 1=English \
   '/tmp/Chinese__Integrated Chinese Level 1 (3rd Edition).apkg' \
   '/tmp/integrated_chinese_deck.apkg' \
-  '/tmp/integrated_chinese_deck.apkg' \
-  --old_c 0 \
-  --old_e 1
+  '/tmp/integrated_chinese_deck.apkg'
 
-./migrate_progress.py \
+cargo run --release -- migrate \
+  --match=identity \
+  --template_map 0=English \
+  --template_map 1=Simplified \
+  --template_map 1=Traditional \
+  --template_map 2=Pinyin \
   '/tmp/Chinese__Integrated Chinese Level 2 (3rd Edition).apkg' \
   '/tmp/integrated_chinese_deck.apkg' \
-  '/tmp/integrated_chinese_deck.apkg' \
-  --int2_hack \
-  --old_e 0 \
-  --old_c 1 \
-  --old_p 2
+  '/tmp/integrated_chinese_deck.apkg'
 
-./deck_subtract.py \
+cargo run --release -- subtract \
   '/tmp/Chinese__Integrated Chinese Level 1 (3rd Edition).apkg' \
   '/tmp/integrated_chinese_deck.apkg' \
   '/tmp/old_integrated_1_pruned.apkg'
 
-./deck_subtract.py \
+cargo run --release -- subtract \
   '/tmp/Chinese__Integrated Chinese Level 2 (3rd Edition).apkg' \
   '/tmp/integrated_chinese_deck.apkg' \
-  '/tmp/old_integrated_2_pruned.apkg' \
-  --int2_hack
+  '/tmp/old_integrated_2_pruned.apkg'
@@ -3,70 +3,488 @@ extern crate time;
 extern crate regex;
 extern crate rusqlite;
 extern crate rustc_serialize;
-extern crate yaml;
+extern crate zip;
 
+use anki21;
+use collection;
+use crypto::digest::Digest;
+use deck_config;
 use rustc_serialize::json;
 use std;
-use std::collections::BTreeMap;
-use std::fs::OpenOptions;
-use yaml::constructor::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-const DECK_ID : i64 = 1428564061183;
-const MODEL_ID : i64 = 1425274727596;
+pub const DECK_ID : i64 = 1428564061183;
+pub const MODEL_ID : i64 = 1425274727596;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+  // the legacy schema 11 collection.anki2, which every version of Anki can import
+  Anki2,
+  // the schema 18 collection.anki21 that Anki 2.1.50+ uses natively
+  Anki21,
+}
+
+impl Format {
+  pub fn from_str(s: &str) -> Option<Format> {
+    match s {
+      "anki2" => Some(Format::Anki2),
+      "anki21" => Some(Format::Anki21),
+      _ => None,
+    }
+  }
+
+  fn collection_filename(&self) -> &'static str {
+    match *self {
+      Format::Anki2 => "collection.anki2",
+      Format::Anki21 => "collection.anki21",
+    }
+  }
+}
+
+fn other_error(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn collection_path(out_path: &Path, col_filename: &str) -> PathBuf {
+  // where the SQLite collection is built before being zipped up into out_path
+  let mut col_path = out_path.as_os_str().to_os_string();
+  col_path.push(".");
+  col_path.push(col_filename);
+  PathBuf::from(col_path)
+}
+
+// A note, card and note type as read back from a collection, for the subcommands that compare or
+// combine existing decks.
+#[derive(Clone)]
+pub struct Note {
+  pub id: i64,
+  pub guid: String,
+  pub mid: i64,
+  pub tags: String,
+  pub flds: Vec<String>,
+  pub sfld: String,
+}
+
+// The part of a card that records the learner's progress. Column meanings are as in Anki's cards
+// table: due is a position for new cards, a timestamp for cards in learning and a day number
+// (relative to the collection's creation time) for cards in review.
+#[derive(Clone, Copy)]
+pub struct Schedule {
+  pub card_type: i64,
+  pub queue: i64,
+  pub due: i64,
+  pub ivl: i64,
+  pub factor: i64,
+  pub reps: i64,
+  pub lapses: i64,
+  pub left: i64,
+}
+
+#[derive(Clone)]
+pub struct Card {
+  pub id: i64,
+  pub nid: i64,
+  pub ord: i64,
+  pub schedule: Schedule,
+}
+
+#[derive(Clone)]
+pub struct ModelSummary {
+  pub id: i64,
+  pub name: String,
+  // field and template names, indexed by ord
+  pub flds: Vec<String>,
+  pub tmpls: Vec<String>,
+}
 
 pub struct AnkiPackage {
   // TODO: make this private
   pub conn: rusqlite::SqliteConnection,
   timespec: time::Timespec,
+  format: Format,
+  // name of the collection inside the .apkg archive; usually format.collection_filename(), but
+  // packages exported by Anki may use collection.anki21 for a schema 11 collection
+  col_filename: String,
+  // whether the package was created by open(), in which case add_note and add_card update
+  // existing notes and cards instead of adding duplicates
+  updating: bool,
+  // models passed to new() or add_model
+  model_ids: HashSet<i64>,
+  // notes passed to add_note
+  added_notes: HashSet<i64>,
+  // name of the top-level deck
+  name: String,
+  // IDs of the sub-decks created so far, keyed by full name
+  subdecks: HashMap<String, i64>,
+  // where the finished .apkg archive is written
+  out_path: PathBuf,
+  // where the SQLite collection is built before being zipped up; lives next to out_path so that we
+  // don't need a writable /tmp
+  col_path: PathBuf,
+  // (filename, contents) of each media file, in the order they'll be numbered in the archive
+  media: Vec<(String, Vec<u8>)>,
+  // maps SHA1 of a media file's contents to the filename it was registered under
+  media_by_hash: HashMap<String, String>,
 }
 
 impl AnkiPackage {
-  // TODO: this hard-codes /tmp/collection.anki2, don't do that
-  pub fn new(name: &str, flds: &str, tmpls_yaml: &str, css: &str) -> AnkiPackage {
-    // make /tmp/collection.anki2 a zero-length file
+  pub fn new(out_path: &Path, format: Format, name: &str, model: &collection::Model,
+             deck_config: &deck_config::DeckConfig) -> AnkiPackage {
+    // creates a package with a top-level deck called name (with ID DECK_ID); model is the default
+    // note type, and more can be added with add_model
+    let col_path = collection_path(out_path, format.collection_filename());
+
+    // make the collection a zero-length file
     OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
-        .open("/tmp/collection.anki2")
+        .open(&col_path)
         .unwrap();
 
-    let rv = AnkiPackage {
-      conn: rusqlite::SqliteConnection::open(
-           &std::path::Path::new("/tmp/collection.anki2")).unwrap(),
+    let mut rv = AnkiPackage {
+      conn: rusqlite::SqliteConnection::open(&col_path).unwrap(),
+      timespec: time::get_time(),
+      format: format,
+      col_filename: format.collection_filename().to_string(),
+      updating: false,
+      model_ids: HashSet::new(),
+      added_notes: HashSet::new(),
+      name: name.to_string(),
+      subdecks: HashMap::new(),
+      out_path: out_path.to_path_buf(),
+      col_path: col_path,
+      media: Vec::new(),
+      media_by_hash: HashMap::new(),
+    };
+    let conf = collection::CollectionConfig::new(DECK_ID, model.id);
+    let deck = collection::Deck::new(DECK_ID, name);
+    match format {
+      Format::Anki2 => {
+        rv.init_legacy_collection(&conf, &deck, deck_config);
+      },
+      Format::Anki21 => {
+        anki21::init_collection(&rv.conn, rv.timespec.sec, &conf, &deck, deck_config);
+      },
+    }
+    rv.add_model(model);
+    rv
+  }
+
+  pub fn open(in_path: &Path, out_path: &Path) -> io::Result<AnkiPackage> {
+    // opens an existing .apkg (or a bare collection.anki2/collection.anki21) so that it can be
+    // updated in place: notes are matched by GUID, and cards by note and ord, so that scheduling
+    // information is kept. The result is written to out_path as usual. Nothing is written to the
+    // collection until it's changed; see add_top_level_deck for adding cards to a collection that
+    // doesn't have our deck yet.
+    AnkiPackage::open_with_flags(
+        in_path, out_path, rusqlite::SQLITE_OPEN_READ_WRITE | rusqlite::SQLITE_OPEN_CREATE)
+  }
+
+  pub fn open_read_only(in_path: &Path, scratch_path: &Path) -> io::Result<AnkiPackage> {
+    // like open, but for collections that are only read from, e.g. a learner's own collection.anki2
+    // from a recent Anki version, which can't be changed here (see below); the working copy of the
+    // collection goes next to scratch_path and should be removed with discard
+    AnkiPackage::open_with_flags(in_path, scratch_path, rusqlite::SQLITE_OPEN_READ_ONLY)
+  }
+
+  fn open_with_flags(in_path: &Path, out_path: &Path, flags: rusqlite::SqliteOpenFlags)
+      -> io::Result<AnkiPackage> {
+    let mut media = Vec::new();
+    let is_apkg = in_path.extension().map(|e| e == "apkg").unwrap_or(false);
+    let (col_filename, col_path) = if is_apkg {
+      let mut archive = try!(zip::ZipArchive::new(try!(File::open(in_path))));
+      let col_filename = if archive.by_name("collection.anki21").is_ok() {
+        "collection.anki21"
+      } else if archive.by_name("collection.anki2").is_ok() {
+        "collection.anki2"
+      } else {
+        return Err(other_error(format!(
+            concat!("{} doesn't contain collection.anki2 or collection.anki21; if it was exported ",
+                    "from a recent version of Anki, export it again with \"Support older Anki ",
+                    "versions\" checked"),
+            in_path.display())));
+      };
+      let col_path = collection_path(out_path, col_filename);
+      {
+        let mut col_file = try!(archive.by_name(col_filename));
+        try!(io::copy(&mut col_file, &mut try!(File::create(&col_path))));
+      }
+
+      let mut manifest_str = String::new();
+      try!(try!(archive.by_name("media")).read_to_string(&mut manifest_str));
+      let manifest = match json::Json::from_str(&manifest_str) {
+        Ok(json::Json::Object(manifest)) => manifest,
+        _ => { return Err(other_error("media manifest wasn't a JSON object".to_string())); },
+      };
+      for (num, name) in manifest {
+        let mut data = Vec::new();
+        try!(try!(archive.by_name(&num)).read_to_end(&mut data));
+        media.push((name.as_string().unwrap_or("").to_string(), data));
+      }
+      (col_filename.to_string(), col_path)
+    } else {
+      let col_filename = in_path.file_name().and_then(|f| f.to_str())
+          .unwrap_or("collection.anki2").to_string();
+      let col_path = collection_path(out_path, &col_filename);
+      try!(std::fs::copy(in_path, &col_path));
+      (col_filename, col_path)
+    };
+
+    // schema 18 collections created by Anki itself use a "unicase" collation that SQLite doesn't
+    // know about, so only ones written by this program can be updated (reading them works, as
+    // long as queries don't go through the indexes on names); a package exported with "Support
+    // older Anki versions" checked holds a schema 11 collection, which always works
+    let conn = try!(rusqlite::SqliteConnection::open_with_flags(&col_path, flags)
+        .map_err(|e| other_error(e.message)));
+    let ver : i64 = try!(conn.query_row_safe("SELECT ver FROM col", &[], |row| row.get(0))
+        .map_err(|e| other_error(e.message)));
+    let format = match ver {
+      11 => Format::Anki2,
+      15...18 => Format::Anki21,
+      _ => { return Err(other_error(format!("unsupported collection schema version {}", ver))); },
+    };
+
+    let mut rv = AnkiPackage {
+      conn: conn,
       timespec: time::get_time(),
+      format: format,
+      col_filename: col_filename,
+      updating: true,
+      model_ids: HashSet::new(),
+      added_notes: HashSet::new(),
+      name: String::new(),
+      subdecks: HashMap::new(),
+      out_path: out_path.to_path_buf(),
+      col_path: col_path,
+      media: Vec::new(),
+      media_by_hash: HashMap::new(),
     };
-    rv.conn.execute_batch(include_str!("apkg_schema.txt")).unwrap();
-    rv.conn.execute_batch(&make_col_sql(
-        &name, &flds, &tmpls_yaml, &css)).unwrap();
+    for (name, data) in media {
+      rv.add_media(&name, data);
+    }
+
+    // existing decks are reused by add_subdeck; if the learner renamed the top-level deck, new
+    // sub-decks go under the new name
+    for (id, deck_name) in rv.deck_names() {
+      if id == DECK_ID {
+        rv.name = deck_name.clone();
+      }
+      rv.subdecks.insert(deck_name, id);
+    }
+    Ok(rv)
+  }
+
+  pub fn add_top_level_deck(&mut self, name: &str) {
+    // creates the deck with ID DECK_ID, called name, if the collection doesn't have it yet (e.g.
+    // the learner deleted it, or the collection is their own rather than one of our decks)
+    if self.subdecks.values().any(|&id| id == DECK_ID) {
+      return;
+    }
+    let deck = collection::Deck::new(DECK_ID, name);
+    self.insert_deck(&deck);
+    self.name = name.to_string();
+    self.subdecks.insert(self.name.clone(), DECK_ID);
+  }
+
+  fn deck_names(&self) -> Vec<(i64, String)> {
+    let mut rv = Vec::new();
+    match self.format {
+      Format::Anki2 => {
+        let decks_str : String =
+            self.conn.query_row("SELECT decks FROM col", &[], |row| row.get(0));
+        match json::Json::from_str(&decks_str) {
+          Ok(json::Json::Object(decks)) => {
+            for (_, deck) in decks {
+              let id = deck.find("id").and_then(|id| id.as_i64()).unwrap_or(0);
+              let name = deck.find("name").and_then(|name| name.as_string()).unwrap_or("");
+              rv.push((id, name.to_string()));
+            }
+          },
+          _ => panic!("col.decks wasn't a JSON object"),
+        }
+      },
+      Format::Anki21 => {
+        let mut stmt = self.conn.prepare("SELECT id, name FROM decks NOT INDEXED").unwrap();
+        for row in stmt.query(&[]).unwrap().map(|row| row.unwrap()) {
+          let name : String = row.get(1);
+          rv.push((row.get(0), name.replace("\x1f", "::")));
+        }
+      },
+    }
     rv
   }
 
-  pub fn add_note(&self, guid: &str, tags: &str, flds: &str, sfld: &str) -> i64 {
-    // returns inserted note's ID
+  fn insert_deck(&self, deck: &collection::Deck) {
+    match self.format {
+      Format::Anki2 => {
+        self.update_legacy_col_json(
+            "decks", deck.id.to_string(), deck.to_json(self.timespec.sec));
+      },
+      Format::Anki21 => anki21::insert_deck(&self.conn, self.timespec.sec, deck),
+    }
+  }
+
+  fn init_legacy_collection(&self, conf: &collection::CollectionConfig, deck: &collection::Deck,
+                            deck_config: &deck_config::DeckConfig) {
+    // schema 11 keeps the models, decks and deck options as JSON objects in the single col row
+    self.conn.execute_batch(include_str!("apkg_schema.txt")).unwrap();
+    let mtime = self.timespec.sec;
+    let mut decks = BTreeMap::new();
+    for d in vec!(collection::Deck::new(1, "Default"), deck.clone()) {
+      decks.insert(d.id.to_string(), d.to_json(mtime));
+    }
+    let mut dconf = BTreeMap::new();
+    dconf.insert("1".to_string(), deck_config.to_json(1));
+    self.conn.execute(
+        "INSERT INTO col VALUES(null,?,?,?,?,?,?,?,?,?,?,?,?);",
+        &[
+            &1411124400,  // crt
+            &(mtime * 1000),  // mod, in milliseconds
+            &(mtime * 1000),  // scm
+            &11,  // ver
+            &0,  // dty
+            &0,  // usn
+            &0,  // ls
+            &json::encode(&conf.to_json()).unwrap(),  // conf
+            &"{}",  // models; filled in by add_model
+            &json::encode(&json::Json::Object(decks)).unwrap(),  // decks
+            &json::encode(&json::Json::Object(dconf)).unwrap(),  // dconf
+            &"{}",  // tags
+        ]).unwrap();
+  }
+
+  fn update_legacy_col_json(&self, column: &str, key: String, val: json::Json) {
+    // sets key to val in the JSON object stored in the given column of the col row
+    let obj_str : String = self.conn.query_row(
+        &format!("SELECT {} FROM col", column), &[], |row| row.get(0));
+    let mut obj = match json::Json::from_str(&obj_str) {
+      Ok(json::Json::Object(obj)) => obj,
+      _ => panic!("col.{} wasn't a JSON object", column),
+    };
+    obj.insert(key, val);
+    self.conn.execute(
+        &format!("UPDATE col SET {} = ?", column),
+        &[&json::encode(&json::Json::Object(obj)).unwrap()]).unwrap();
+  }
+
+  pub fn add_model(&mut self, model: &collection::Model) {
+    // makes model available to add_note, replacing any existing model with the same ID
+    match self.format {
+      Format::Anki2 => {
+        self.update_legacy_col_json(
+            "models", model.id.to_string(), model.to_json(self.timespec.sec));
+      },
+      Format::Anki21 => anki21::insert_notetype(&self.conn, self.timespec.sec, model),
+    }
+    if self.updating {
+      // existing notes need to have as many fields as the model now does
+      let mut notes : Vec<(i64, String)> = Vec::new();
+      {
+        let mut stmt = self.conn.prepare("SELECT id, flds FROM notes WHERE mid = ?").unwrap();
+        for row in stmt.query(&[&model.id]).unwrap().map(|row| row.unwrap()) {
+          notes.push((row.get(0), row.get(1)));
+        }
+      }
+      for (note_id, flds) in notes {
+        let mut fld_vec : Vec<&str> = flds.split('\x1f').collect();
+        if fld_vec.len() == model.flds.len() {
+          continue;
+        }
+        fld_vec.resize(model.flds.len(), "");
+        self.conn.execute(
+            "UPDATE notes SET flds = ?, mod = ?, usn = -1 WHERE id = ?",
+            &[&fld_vec.connect("\x1f"), &self.timespec.sec, &note_id]).unwrap();
+      }
+    }
+    self.model_ids.insert(model.id);
+  }
+
+  pub fn add_note(&mut self, model: &collection::Model, guid: &str, tags: &str, flds: &str)
+      -> i64 {
+    // returns inserted note's ID; model must have been passed to new() or add_model(). The sort
+    // field and checksum are computed the same way Anki does it for notes created in the app.
+    let fld_vec : Vec<&str> = flds.split('\x1f').collect();
+    if fld_vec.len() != model.flds.len() {
+      panic!("note has {} fields, but the {} note type has {}",
+             fld_vec.len(), model.name, model.flds.len());
+    }
+
+    if self.updating {
+      let existing = self.conn.query_row_safe(
+          "SELECT id, tags FROM notes WHERE guid = ?", &[&guid],
+          |row| (row.get::<i64>(0), row.get::<String>(1)));
+      match existing {
+        Ok((note_id, old_tags)) => {
+          // keep any tags the learner added (e.g. "leech" or "marked")
+          let mut new_tags : Vec<&str> = tags.split(' ').filter(|t| *t != "").collect();
+          for tag in old_tags.split(' ').filter(|t| *t != "") {
+            if !new_tags.contains(&tag) {
+              new_tags.push(tag);
+            }
+          }
+          let new_tags = " ".to_string() + &new_tags.connect(" ") + " ";
+          self.conn.execute(
+              concat!("UPDATE notes SET mid = ?, mod = ?, usn = -1, tags = ?, flds = ?, sfld = ?,",
+                      " csum = ? WHERE id = ? AND (mid != ? OR tags != ? OR flds != ?)"),
+              &[
+                  &model.id,
+                  &self.timespec.sec,
+                  &new_tags,
+                  &flds,
+                  &strip_html(fld_vec[model.sortf as usize]),
+                  &field_checksum(fld_vec[0]),
+                  &note_id,
+                  &model.id,
+                  &new_tags,
+                  &flds,
+              ]).unwrap();
+          self.added_notes.insert(note_id);
+          return note_id;
+        },
+        Err(_) => (),
+      }
+    }
+
     self.conn.execute(
     "INSERT INTO notes VALUES(null,?,?,?,?,?,?,?,?,?,?);",
     &[
         &guid,
-        &MODEL_ID,  // mid
+        &model.id,  // mid
         &self.timespec.sec,  // mod
         &-1,  // usn
         &tags,  // tags
         &flds,  // flds
-        &sfld,  // sfld
-        &0,  // csum, can be ignored
+        &strip_html(fld_vec[model.sortf as usize]),  // sfld
+        &field_checksum(fld_vec[0]),  // csum
         &0,  // flags
         &"",  // data
     ]).unwrap();
-    self.conn.last_insert_rowid()
+    let note_id = self.conn.last_insert_rowid();
+    self.added_notes.insert(note_id);
+    note_id
   }
 
-  pub fn add_card(&self, note_id: i64, ord: i64) {
+  pub fn add_card(&self, note_id: i64, deck_id: i64, ord: i64) -> i64 {
+    // deck_id is either DECK_ID or a value returned by add_subdeck; returns the card's ID
+    if self.updating {
+      // existing cards keep their scheduling, and stay in whatever deck the learner put them in
+      let existing = self.conn.query_row_safe(
+          "SELECT id FROM cards WHERE nid = ? AND ord = ?", &[&note_id, &ord],
+          |row| row.get::<i64>(0));
+      if let Ok(card_id) = existing {
+        return card_id;
+      }
+    }
     self.conn.execute(
         "INSERT INTO cards VALUES(null,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?);",
         &[
             &note_id,  // nid
-            &DECK_ID,  // did
+            &deck_id,  // did
             &ord,  // ord
             &self.timespec.sec,  // mod
             &-1,  // usn
@@ -83,47 +501,534 @@ impl AnkiPackage {
             &0,  // flags
             &"".to_string(),  // data
     ]).unwrap();
+    // new cards are shown in the order they were added; only the cards inserted here are numbered,
+    // so that the learner's other new cards keep their order
+    let card_id = self.conn.last_insert_rowid();
+    self.conn.execute("UPDATE cards SET due = ? WHERE id = ?", &[&(card_id + 1), &card_id])
+        .unwrap();
+    card_id
+  }
+
+  pub fn add_subdeck(&mut self, subdeck: &str) -> i64 {
+    // returns the ID of the deck NAME::subdeck, creating it and any intermediate decks (e.g.
+    // NAME::L1 for L1::Lesson 04) as needed
+    let mut full_name = self.name.clone();
+    let mut id = DECK_ID;
+    for component in subdeck.split("::") {
+      full_name = full_name + "::" + component;
+      id = match self.subdecks.get(&full_name) {
+        Some(&id) => id,
+        None => {
+          let deck = collection::Deck::new(self.new_deck_id(&full_name), &full_name);
+          self.insert_deck(&deck);
+          deck.id
+        },
+      };
+      self.subdecks.insert(full_name.clone(), id);
+    }
+    id
+  }
+
+  fn new_deck_id(&self, name: &str) -> i64 {
+    // an ID for a new deck called name that no existing deck has (including DECK_ID, which a
+    // copied deck would otherwise keep if it was another package's top-level deck)
+    let mut id = deck_id_from_name(name);
+    while id == DECK_ID || self.subdecks.values().any(|&other| other == id) {
+      id += 1;
+    }
+    id
+  }
+
+  pub fn remove_note(&self, note_id: i64) {
+    // deletes a note and its cards; when updating an existing collection, the deletions are
+    // recorded in graves so that they propagate when the collection is synced
+    if self.updating {
+      let mut card_ids : Vec<i64> = Vec::new();
+      {
+        let mut stmt = self.conn.prepare("SELECT id FROM cards WHERE nid = ?").unwrap();
+        for row in stmt.query(&[&note_id]).unwrap().map(|row| row.unwrap()) {
+          card_ids.push(row.get(0));
+        }
+      }
+      for card_id in card_ids {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO graves (usn, oid, type) VALUES (-1, ?, 0)",
+            &[&card_id]).unwrap();
+      }
+      self.conn.execute(
+          "INSERT OR REPLACE INTO graves (usn, oid, type) VALUES (-1, ?, 1)",
+          &[&note_id]).unwrap();
+    }
+    self.conn.execute("DELETE FROM cards WHERE nid = ?", &[&note_id]).unwrap();
+    self.conn.execute("DELETE FROM notes WHERE id = ?", &[&note_id]).unwrap();
+  }
+
+  pub fn keep_note(&mut self, guid: &str) {
+    // marks the existing note with this GUID, if there is one, as still part of the deck without
+    // changing it, so that remove_missing_notes leaves it and its cards alone
+    if self.updating {
+      match self.conn.query_row_safe(
+          "SELECT id FROM notes WHERE guid = ?", &[&guid], |row| row.get::<i64>(0)) {
+        Ok(note_id) => { self.added_notes.insert(note_id); },
+        Err(_) => (),
+      }
+    }
+  }
+
+  pub fn remove_missing_notes(&self) -> usize {
+    // removes notes that use one of our models but weren't passed to add_note, i.e. words that are
+    // no longer part of the deck; returns how many were removed. Notes of other note types are
+    // left alone.
+    let mut to_remove = Vec::new();
+    {
+      let mut stmt = self.conn.prepare("SELECT id, mid FROM notes").unwrap();
+      for row in stmt.query(&[]).unwrap().map(|row| row.unwrap()) {
+        let note_id : i64 = row.get(0);
+        let mid : i64 = row.get(1);
+        if self.model_ids.contains(&mid) && !self.added_notes.contains(&note_id) {
+          to_remove.push(note_id);
+        }
+      }
+    }
+    for &note_id in &to_remove {
+      self.remove_note(note_id);
+    }
+    to_remove.len()
+  }
+
+  pub fn notes(&self) -> Vec<Note> {
+    let mut rv = Vec::new();
+    let mut stmt = self.conn.prepare("SELECT id, guid, mid, tags, flds, sfld FROM notes").unwrap();
+    for row in stmt.query(&[]).unwrap().map(|row| row.unwrap()) {
+      let flds : String = row.get(4);
+      rv.push(Note {
+        id: row.get(0),
+        guid: row.get(1),
+        mid: row.get(2),
+        tags: row.get(3),
+        flds: flds.split('\x1f').map(|f| f.to_string()).collect(),
+        sfld: row.get(5),
+      });
+    }
+    rv
+  }
+
+  pub fn cards(&self) -> Vec<Card> {
+    let mut rv = Vec::new();
+    let mut stmt = self.conn.prepare(concat!(
+        "SELECT id, nid, ord, type, queue, due, ivl, factor, reps, lapses, left FROM cards"))
+        .unwrap();
+    for row in stmt.query(&[]).unwrap().map(|row| row.unwrap()) {
+      rv.push(Card {
+        id: row.get(0),
+        nid: row.get(1),
+        ord: row.get(2),
+        schedule: Schedule {
+          card_type: row.get(3),
+          queue: row.get(4),
+          due: row.get(5),
+          ivl: row.get(6),
+          factor: row.get(7),
+          reps: row.get(8),
+          lapses: row.get(9),
+          left: row.get(10),
+        },
+      });
+    }
+    rv
+  }
+
+  pub fn models(&self) -> Vec<ModelSummary> {
+    let mut rv = Vec::new();
+    match self.format {
+      Format::Anki2 => {
+        let models_str : String =
+            self.conn.query_row("SELECT models FROM col", &[], |row| row.get(0));
+        let models = match json::Json::from_str(&models_str) {
+          Ok(json::Json::Object(models)) => models,
+          _ => panic!("col.models wasn't a JSON object"),
+        };
+        for (id, model) in models {
+          rv.push(ModelSummary {
+            id: id.parse().unwrap_or(0),
+            name: model.find("name").and_then(|name| name.as_string()).unwrap_or("").to_string(),
+            flds: legacy_names(&model, "flds"),
+            tmpls: legacy_names(&model, "tmpls"),
+          });
+        }
+      },
+      Format::Anki21 => {
+        let mut stmt = self.conn.prepare("SELECT id, name FROM notetypes NOT INDEXED").unwrap();
+        for row in stmt.query(&[]).unwrap().map(|row| row.unwrap()) {
+          rv.push(ModelSummary {
+            id: row.get(0),
+            name: row.get(1),
+            flds: Vec::new(),
+            tmpls: Vec::new(),
+          });
+        }
+        for model in &mut rv {
+          model.flds = self.anki21_names("fields", model.id);
+          model.tmpls = self.anki21_names("templates", model.id);
+        }
+      },
+    }
+    rv
+  }
+
+  fn anki21_names(&self, table: &str, ntid: i64) -> Vec<String> {
+    // names of the fields or templates of a schema 18 note type, in ord order
+    let mut rv = Vec::new();
+    let mut stmt = self.conn.prepare(
+        &format!("SELECT name FROM {} NOT INDEXED WHERE ntid = ? ORDER BY ord", table)).unwrap();
+    for row in stmt.query(&[&ntid]).unwrap().map(|row| row.unwrap()) {
+      rv.push(row.get(0));
+    }
+    rv
+  }
+
+  pub fn creation_time(&self) -> i64 {
+    // the collection's crt, which review due dates are counted from
+    self.conn.query_row("SELECT crt FROM col", &[], |row| row.get(0))
+  }
+
+  pub fn set_schedule(&self, card_id: i64, schedule: &Schedule) {
+    self.conn.execute(
+        concat!("UPDATE cards SET mod = ?, usn = -1, type = ?, queue = ?, due = ?, ivl = ?, ",
+                "factor = ?, reps = ?, lapses = ?, left = ? WHERE id = ?"),
+        &[
+            &self.timespec.sec,
+            &schedule.card_type,
+            &schedule.queue,
+            &schedule.due,
+            &schedule.ivl,
+            &schedule.factor,
+            &schedule.reps,
+            &schedule.lapses,
+            &schedule.left,
+            &card_id,
+        ]).unwrap();
+  }
+
+  pub fn copy_note(&mut self, other: &AnkiPackage, note: &Note) -> Result<i64, String> {
+    // adds a note from another collection, along with its note type (if this collection doesn't
+    // have it yet) and its cards, which keep their scheduling and go into decks of the same names;
+    // returns the new note's ID
+    if self.format != other.format {
+      return Err("can't copy notes between collections of different formats".to_string());
+    }
+    if !self.models().iter().any(|m| m.id == note.mid) {
+      match self.format {
+        Format::Anki2 => {
+          let models_str : String =
+              other.conn.query_row("SELECT models FROM col", &[], |row| row.get(0));
+          let model = match json::Json::from_str(&models_str) {
+            Ok(json::Json::Object(mut models)) => models.remove(&note.mid.to_string()),
+            _ => None,
+          };
+          match model {
+            Some(model) => self.update_legacy_col_json("models", note.mid.to_string(), model),
+            None => { return Err(format!("note type {} is missing", note.mid)); },
+          }
+        },
+        Format::Anki21 => anki21::copy_notetype(&other.conn, &self.conn, note.mid),
+      }
+    }
+
+    let flds = note.flds.connect("\x1f");
+    self.conn.execute(
+        "INSERT INTO notes VALUES(null,?,?,?,?,?,?,?,?,?,?);",
+        &[
+            &note.guid,
+            &note.mid,
+            &self.timespec.sec,  // mod
+            &-1,  // usn
+            &note.tags,
+            &flds,
+            &note.sfld,
+            &field_checksum(&note.flds[0]),  // csum
+            &0,  // flags
+            &"",  // data
+        ]).unwrap();
+    let note_id = self.conn.last_insert_rowid();
+
+    let other_decks : HashMap<i64, String> = other.deck_names().into_iter().collect();
+    let mut cards = Vec::new();
+    {
+      let mut stmt = other.conn.prepare(concat!(
+          "SELECT did, ord, type, queue, due, ivl, factor, reps, lapses, left FROM cards ",
+          "WHERE nid = ?")).unwrap();
+      for row in stmt.query(&[&note.id]).unwrap().map(|row| row.unwrap()) {
+        let did : i64 = row.get(0);
+        let ord : i64 = row.get(1);
+        cards.push((did, ord, Schedule {
+          card_type: row.get(2),
+          queue: row.get(3),
+          due: row.get(4),
+          ivl: row.get(5),
+          factor: row.get(6),
+          reps: row.get(7),
+          lapses: row.get(8),
+          left: row.get(9),
+        }));
+      }
+    }
+    for (did, ord, schedule) in cards {
+      let deck_name = other_decks.get(&did).map(|name| name.clone())
+          .unwrap_or(self.name.clone());
+      let deck_id = match self.subdecks.get(&deck_name) {
+        Some(&id) => id,
+        None => {
+          let deck = collection::Deck::new(self.new_deck_id(&deck_name), &deck_name);
+          self.insert_deck(&deck);
+          deck.id
+        },
+      };
+      self.subdecks.insert(deck_name, deck_id);
+      let card_id = self.add_card(note_id, deck_id, ord);
+      self.set_schedule(card_id, &schedule);
+    }
+    self.added_notes.insert(note_id);
+    Ok(note_id)
+  }
+
+  pub fn add_media(&mut self, filename: &str, data: Vec<u8>) -> String {
+    // registers a media file to be shipped in the package, and returns the filename that note
+    // fields should use to refer to it (see sound_tag and img_tag); files with identical contents
+    // are only stored once
+    let mut sha = crypto::sha1::Sha1::new();
+    sha.input(&data);
+    let hash = sha.result_str();
+    if let Some(existing) = self.media_by_hash.get(&hash) {
+      return existing.clone();
+    }
+
+    let mut name = filename.to_string();
+    if self.media.iter().any(|&(ref n, _)| *n == name) {
+      // a file with different contents already has this name, so disambiguate using the hash
+      name = match filename.rfind('.') {
+        Some(i) => format!("{}-{}{}", &filename[..i], &hash[..8], &filename[i..]),
+        None => format!("{}-{}", filename, &hash[..8]),
+      };
+    }
+    self.media_by_hash.insert(hash, name.clone());
+    self.media.push((name.clone(), data));
+    name
+  }
+
+  pub fn write(self) -> io::Result<()> {
+    // closes the collection and writes it, along with the media manifest, to the .apkg archive at
+    // the path passed to new(); the intermediate collection file is removed afterwards, even if
+    // writing failed
+    let AnkiPackage { conn, col_filename, out_path, col_path, media, .. } = self;
+    let written = AnkiPackage::write_archive(conn, &col_filename, &out_path, &col_path, &media);
+    let removed = std::fs::remove_file(&col_path);
+    written.and(removed)
+  }
+
+  fn write_archive(conn: rusqlite::SqliteConnection, col_filename: &str, out_path: &Path,
+                   col_path: &Path, media: &Vec<(String, Vec<u8>)>) -> io::Result<()> {
+    try!(conn.close().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+
+    let mut col_bytes = Vec::new();
+    try!(File::open(&col_path).and_then(|mut f| f.read_to_end(&mut col_bytes)));
+
+    let mut archive = zip::ZipWriter::new(try!(File::create(&out_path)));
+    let options = zip::write::FileOptions::default();
+    try!(archive.start_file(col_filename.to_string(), options));
+    try!(archive.write_all(&col_bytes));
+    // media files are stored as "0", "1", ..., and the "media" manifest maps those entry names back
+    // to the real filenames
+    let mut manifest = BTreeMap::new();
+    for (i, &(ref name, ref data)) in media.iter().enumerate() {
+      try!(archive.start_file(i.to_string(), options));
+      try!(archive.write_all(data));
+      manifest.insert(i.to_string(), name.clone());
+    }
+    try!(archive.start_file("media", options));
+    try!(archive.write_all(json::encode(&manifest).unwrap().as_bytes()));
+    try!(archive.finish());
+    Ok(())
+  }
+
+  pub fn discard(self) -> io::Result<()> {
+    // closes the collection without writing a package, e.g. for one that was only read from
+    let AnkiPackage { conn, col_path, .. } = self;
+    try!(conn.close().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+    std::fs::remove_file(&col_path)
   }
 }
 
-fn yaml_string(y: YamlStandardData) -> String {
-  match y {
-    YamlStandardData::YamlString(s) => s,
-    _ => panic!("data wasn't a string"),
+pub fn sound_tag(filename: &str) -> String {
+  // field markup that plays a media file registered with AnkiPackage::add_media
+  format!("[sound:{}]", filename)
+}
+
+pub fn img_tag(filename: &str) -> String {
+  // field markup that displays a media file registered with AnkiPackage::add_media
+  format!("<img src=\"{}\">", filename.replace("\"", "&quot;"))
+}
+
+fn legacy_names(model: &json::Json, key: &str) -> Vec<String> {
+  // names of the fields (key = "flds") or templates ("tmpls") of a schema 11 model, in ord order
+  let mut items : Vec<(u64, String)> = Vec::new();
+  for item in model.find(key).and_then(|items| items.as_array()).unwrap_or(&Vec::new()) {
+    items.push((
+        item.find("ord").and_then(|ord| ord.as_u64()).unwrap_or(0),
+        item.find("name").and_then(|name| name.as_string()).unwrap_or("").to_string()));
   }
+  items.sort();
+  items.into_iter().map(|(_, name)| name).collect()
 }
 
-fn make_col_sql(name: &str, flds_json: &str, tmpls_yaml: &str, css: &str) -> String {
-  let mut tmpls = Vec::new();
-  let yaml_doc = yaml::parse_bytes_utf8(tmpls_yaml.as_bytes())
-      .unwrap()
-      .pop();
-  let seq = match yaml_doc {
-    Some(YamlStandardData::YamlSequence(s)) => s,
-    _ => panic!("data wasn't a sequence"),
-  };
-  let mut ord = 0;
-  for item in seq {
-    let map = match item {
-      YamlStandardData::YamlMapping(m) => m,
-      _ => panic!("data wasn't a mapping"),
-    };
-    let mut outmap = BTreeMap::new();
-    for (key, val) in map {
-      outmap.insert(yaml_string(key), json::Json::String(yaml_string(val)));
-    }
-    outmap.insert("bafmt".to_string(), json::Json::String("".to_string()));
-    outmap.insert("bqfmt".to_string(), json::Json::String("".to_string()));
-    outmap.insert("did".to_string(), json::Json::Null);
-    outmap.insert("ord".to_string(), json::Json::I64(ord));
-    ord += 1;
-    tmpls.push(outmap);
+fn deck_id_from_name(name: &str) -> i64 {
+  // derive sub-deck IDs from their names, so that they stay the same from one build to the next
+  let mut sha = crypto::sha2::Sha256::new();
+  sha.input_str(name);
+  let mut sha_out : [u8; 32] = [0; 32];
+  sha.result(&mut sha_out);
+  let mut val : i64 = 0;
+  for i in 0..6 {
+    val <<= 8;
+    val += sha_out[i] as i64;
   }
+  val
+}
 
-  include_str!("apkg_col.txt")
-      .replace("NAME", &name)
-      .replace("FLDS", &flds_json)
-      .replace("TMPLS", &json::encode(&tmpls).unwrap())
-      .replace("CARDCSS", &json::encode(&css).unwrap())
+pub fn strip_html(s: &str) -> String {
+  // mirrors Anki's stripHTMLMedia: drops comments and tags (but keeps the filenames of images), and
+  // decodes the common entities
+  let comment_re = regex!(r"(?s)<!--.*?-->");
+  let img_re = regex!(r#"(?i)<img[^>]+src=["']?([^"'>]+)["']?[^>]*>"#);
+  let tag_re = regex!(r"(?s)<.*?>");
+  let s = comment_re.replace_all(s, "");
+  let s = img_re.replace_all(&s, " $1 ");
+  let s = tag_re.replace_all(&s, "");
+  s.replace("&nbsp;", " ")
+      .replace("&lt;", "<")
+      .replace("&gt;", ">")
+      .replace("&quot;", "\"")
+      .replace("&amp;", "&")
+      .trim()
+      .to_string()
 }
 
+fn field_checksum(fld: &str) -> i64 {
+  // Anki's csum: the first 8 hex digits of the SHA1 of the stripped field, as an integer
+  let mut sha = crypto::sha1::Sha1::new();
+  sha.input_str(&strip_html(fld));
+  i64::from_str_radix(&sha.result_str()[..8], 16).unwrap()
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use deck_config;
+  use note_types;
+  use std;
+  use std::path::PathBuf;
+
+  pub fn scratch_path(name: &str) -> PathBuf {
+    // a path in the temporary directory for a test's packages
+    std::env::temp_dir().join(format!("hsk_flashcards_test_{}", name))
+  }
+
+  pub fn fields(simp: &str, n: usize) -> String {
+    // the fields of a note with n fields, of which only the first is filled in
+    let mut rv = vec!(simp);
+    rv.resize(n, "");
+    rv.connect("\x1f")
+  }
+
+  #[test]
+  fn test_update_round_trip() {
+    for &(format, suffix) in &[(Format::Anki2, "anki2"), (Format::Anki21, "anki21")] {
+      let built = scratch_path(&format!("round_trip_{}.apkg", suffix));
+      let updated = scratch_path(&format!("round_trip_{}_updated.apkg", suffix));
+      let model = note_types::word_model("HSK", false);
+      // an older version of the note type, with fewer fields
+      let mut old_model = model.clone();
+      old_model.flds.truncate(model.flds.len() - 2);
+
+      let mut apkg =
+          AnkiPackage::new(&built, format, "HSK", &old_model, &deck_config::DeckConfig::new());
+      for &(guid, simp) in &[("a", "你"), ("b", "好")] {
+        let note_id =
+            apkg.add_note(&old_model, guid, " HSK_Level_1 ", &fields(simp, old_model.flds.len()));
+        apkg.add_card(note_id, DECK_ID, 0);
+      }
+      // the learner tags 你 and reviews it
+      apkg.conn.execute("UPDATE notes SET tags = ' HSK_Level_1 leech ' WHERE guid = 'a'", &[])
+          .unwrap();
+      apkg.conn.execute("UPDATE cards SET type = 2, queue = 2, ivl = 10", &[]).unwrap();
+      apkg.write().unwrap();
+
+      let mut apkg = AnkiPackage::open(&built, &updated).unwrap();
+      apkg.add_top_level_deck("HSK");
+      apkg.add_model(&model);
+      // existing notes are padded out to the new note type's fields
+      assert!(apkg.notes().iter().all(|note| note.flds.len() == model.flds.len()));
+      let note_id = apkg.add_note(&model, "a", " HSK_Level_1 ", &fields("你", model.flds.len()));
+      apkg.add_card(note_id, DECK_ID, 0);
+      assert_eq!(apkg.remove_missing_notes(), 1);
+
+      let notes = apkg.notes();
+      assert_eq!(notes.len(), 1);
+      assert_eq!((notes[0].id, &notes[0].guid as &str), (note_id, "a"));
+      assert_eq!(notes[0].tags, " HSK_Level_1 leech ");
+      let cards = apkg.cards();
+      assert_eq!(cards.len(), 1);
+      assert_eq!((cards[0].nid, cards[0].schedule.ivl), (note_id, 10));
+      apkg.write().unwrap();
+      std::fs::remove_file(&built).unwrap();
+      std::fs::remove_file(&updated).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_keep_note() {
+    let built = scratch_path("keep_note.apkg");
+    let updated = scratch_path("keep_note_updated.apkg");
+    let model = note_types::word_model("HSK", false);
+    let mut apkg =
+        AnkiPackage::new(&built, Format::Anki2, "HSK", &model, &deck_config::DeckConfig::new());
+    for &(guid, simp) in &[("a", "你"), ("b", "好"), ("c", "的")] {
+      let note_id = apkg.add_note(&model, guid, "", &fields(simp, model.flds.len()));
+      apkg.add_card(note_id, DECK_ID, 0);
+    }
+    apkg.write().unwrap();
+
+    // 好 is now skipped (e.g. by --known_words), and 的 is no longer part of the deck
+    let mut apkg = AnkiPackage::open(&built, &updated).unwrap();
+    apkg.add_top_level_deck("HSK");
+    apkg.add_model(&model);
+    apkg.add_note(&model, "a", "", &fields("你", model.flds.len()));
+    apkg.keep_note("b");
+    apkg.keep_note("d");
+    assert_eq!(apkg.remove_missing_notes(), 1);
+    let mut guids : Vec<String> = apkg.notes().into_iter().map(|note| note.guid).collect();
+    guids.sort();
+    assert_eq!(guids, vec!("a", "b"));
+    assert_eq!(apkg.cards().len(), 2);
+    apkg.discard().unwrap();
+    std::fs::remove_file(&built).unwrap();
+  }
+
+  #[test]
+  fn test_media_tags() {
+    assert_eq!(sound_tag("你好.mp3"), "[sound:你好.mp3]");
+    assert_eq!(img_tag("你好.svg"), "<img src=\"你好.svg\">");
+  }
+
+  #[test]
+  fn test_strip_html() {
+    assert_eq!(super::strip_html("<span class=\"nobr\">你好</span>&nbsp;"), "你好");
+    assert_eq!(super::strip_html("a<!-- comment -->b &amp; <img src=\"c.png\">"), "ab &  c.png");
+  }
+
+  #[test]
+  fn test_field_checksum() {
+    // SHA1("abc") is a9993e36...
+    assert_eq!(super::field_checksum("<b>abc</b>"), 0xa9993e36);
+  }
+}
@@ -0,0 +1,143 @@
+extern crate rusqlite;
+extern crate rustc_serialize;
+
+use collection;
+use deck_config;
+use protobuf;
+use rustc_serialize::json;
+
+// Newer versions of Anki (2.1.50+) natively use schema 18, where the note types, decks and deck
+// options no longer live as JSON blobs in the col row; instead each gets its own table, with the
+// settings encoded as protobuf messages. The notes, cards and revlog tables are unchanged, so
+// AnkiPackage fills them in the same way for both formats.
+
+const DEFAULT_DECK_ID : i64 = 1;
+const DEFAULT_DECK_CONF_ID : i64 = 1;
+
+pub fn init_collection(conn: &rusqlite::SqliteConnection, mtime: i64,
+                       conf: &collection::CollectionConfig, deck: &collection::Deck,
+                       deck_config: &deck_config::DeckConfig) {
+  conn.execute_batch(include_str!("anki21_schema.txt")).unwrap();
+  for (key, val) in conf.items() {
+    conn.execute(
+        "INSERT INTO config VALUES(?,?,?,?);",
+        &[&key, &-1, &mtime, &json::encode(&val).unwrap().into_bytes()]).unwrap();
+  }
+  insert_deck_config(conn, mtime, deck_config);
+  insert_deck(conn, mtime, &collection::Deck::new(DEFAULT_DECK_ID, "Default"));
+  insert_deck(conn, mtime, deck);
+}
+
+fn insert_deck_config(conn: &rusqlite::SqliteConnection, mtime: i64,
+                      deck_config: &deck_config::DeckConfig) {
+  conn.execute(
+      "INSERT INTO deck_config VALUES(?,?,?,?,?);",
+      &[
+          &DEFAULT_DECK_CONF_ID,
+          &"Default",
+          &mtime,
+          &-1,  // usn
+          &deck_config.to_protobuf().bytes(),
+      ]).unwrap();
+}
+
+pub fn insert_deck(conn: &rusqlite::SqliteConnection, mtime: i64, deck: &collection::Deck) {
+  let mut normal = protobuf::Message::new();
+  normal
+      .int(1, deck.conf)  // config_id
+      .string(4, &deck.desc);  // description
+  let mut kind = protobuf::Message::new();
+  kind.message(1, &normal);  // normal
+  conn.execute(
+      "INSERT INTO decks VALUES(?,?,?,?,?,?);",
+      &[
+          &deck.id,
+          // schema 18 separates the components of a deck's name with \x1f instead of ::
+          &deck.name.replace("::", "\x1f"),
+          &mtime,
+          &-1,  // usn
+          &protobuf::Message::new().bytes(),  // common
+          &kind.bytes(),
+      ]).unwrap();
+}
+
+pub fn insert_notetype(conn: &rusqlite::SqliteConnection, mtime: i64, model: &collection::Model) {
+  // replaces any existing note type with the same ID, including its fields and templates
+  let mut config = protobuf::Message::new();
+  config
+      .uint(1, match model.kind {  // kind
+        collection::ModelKind::Standard => 0,
+        collection::ModelKind::Cloze => 1,
+      })
+      .uint(2, model.sortf as u64)  // sort_field_idx
+      .string(3, &model.css)
+      .string(5, &model.latex_pre)
+      .string(6, &model.latex_post);
+  conn.execute(
+      "INSERT OR REPLACE INTO notetypes VALUES(?,?,?,?,?);",
+      &[&model.id, &model.name, &mtime, &-1, &config.bytes()]).unwrap();
+  conn.execute("DELETE FROM fields WHERE ntid = ?", &[&model.id]).unwrap();
+  conn.execute("DELETE FROM templates WHERE ntid = ?", &[&model.id]).unwrap();
+
+  for fld in &model.flds {
+    let mut fld_config = protobuf::Message::new();
+    fld_config
+        .bool(1, fld.sticky)
+        .bool(2, fld.rtl)
+        .string(3, &fld.font)  // font_name
+        .uint(4, fld.size as u64);  // font_size
+    conn.execute(
+        "INSERT INTO fields VALUES(?,?,?,?);",
+        &[&model.id, &(fld.ord as i64), &fld.name, &fld_config.bytes()]).unwrap();
+  }
+
+  for tmpl in &model.tmpls {
+    let mut tmpl_config = protobuf::Message::new();
+    tmpl_config
+        .string(1, &tmpl.qfmt)
+        .string(2, &tmpl.afmt)
+        .string(3, &tmpl.bqfmt)
+        .string(4, &tmpl.bafmt)
+        .int(5, tmpl.did.unwrap_or(0));  // target_deck_id
+    conn.execute(
+        "INSERT INTO templates VALUES(?,?,?,?,?,?);",
+        &[&model.id, &(tmpl.ord as i64), &tmpl.name, &mtime, &-1, &tmpl_config.bytes()]).unwrap();
+  }
+}
+
+pub fn copy_notetype(from: &rusqlite::SqliteConnection, to: &rusqlite::SqliteConnection,
+                     ntid: i64) {
+  // copies a note type, with its fields and templates, from one schema 18 collection to another;
+  // from may be a learner's collection, whose indexes on names can't be used (see
+  // AnkiPackage::open)
+  {
+    let mut stmt = from.prepare(
+        "SELECT id, name, mtime_secs, usn, config FROM notetypes NOT INDEXED WHERE id = ?")
+        .unwrap();
+    for row in stmt.query(&[&ntid]).unwrap().map(|row| row.unwrap()) {
+      to.execute(
+          "INSERT INTO notetypes VALUES(?,?,?,?,?);",
+          &[&row.get::<i64>(0), &row.get::<String>(1), &row.get::<i64>(2), &row.get::<i64>(3),
+            &row.get::<Vec<u8>>(4)]).unwrap();
+    }
+  }
+  {
+    let mut stmt = from.prepare(
+        "SELECT ntid, ord, name, config FROM fields NOT INDEXED WHERE ntid = ?").unwrap();
+    for row in stmt.query(&[&ntid]).unwrap().map(|row| row.unwrap()) {
+      to.execute(
+          "INSERT INTO fields VALUES(?,?,?,?);",
+          &[&row.get::<i64>(0), &row.get::<i64>(1), &row.get::<String>(2),
+            &row.get::<Vec<u8>>(3)]).unwrap();
+    }
+  }
+  let mut stmt = from.prepare(concat!(
+      "SELECT ntid, ord, name, mtime_secs, usn, config FROM templates NOT INDEXED ",
+      "WHERE ntid = ?")).unwrap();
+  for row in stmt.query(&[&ntid]).unwrap().map(|row| row.unwrap()) {
+    to.execute(
+        "INSERT INTO templates VALUES(?,?,?,?,?,?);",
+        &[&row.get::<i64>(0), &row.get::<i64>(1), &row.get::<String>(2), &row.get::<i64>(3),
+          &row.get::<i64>(4), &row.get::<Vec<u8>>(5)]).unwrap();
+  }
+}
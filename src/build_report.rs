@@ -0,0 +1,201 @@
+extern crate csv;
+extern crate rustc_serialize;
+
+use cedict;
+use chinese_note;
+use parse_error::ParseError;
+use rustc_serialize::json;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+// A summary of the judgement calls made while building a deck (words that were left out, words
+// with several dictionary entries and how one was picked, duplicate notes that were merged, words
+// skipped by --known_words), so that preferred_entries.yaml and the extra dictionary files can be
+// curated. Written by --report.
+
+#[derive(Clone, PartialEq, Debug, RustcEncodable)]
+pub struct Problem {
+  pub file: String,
+  pub line: Option<usize>,
+  pub message: String,
+}
+
+// A word whose entry wasn't an obvious choice: it had several entries, or none of them was good.
+#[derive(Clone, PartialEq, Debug, RustcEncodable)]
+pub struct Lookup {
+  pub word: String,
+  // see chinese_note::Rule::name
+  pub rule: String,
+  // entries are written as CC-CEDICT lines
+  pub chosen: String,
+  // the other entries shown on the note (see ChineseNote::other_entries)
+  pub also_shown: Vec<String>,
+  pub candidates: Vec<String>,
+}
+
+// A note that was merged into an earlier one because they had the same entries (see
+// chinese_note::merge_duplicates).
+#[derive(Clone, PartialEq, Debug, RustcEncodable)]
+pub struct Duplicate {
+  pub word: String,
+}
+
+// A word that was left out because the learner already knows it (see --known_words).
+#[derive(Clone, PartialEq, Debug, RustcEncodable)]
+pub struct Known {
+  pub word: String,
+}
+
+#[derive(Clone, PartialEq, Debug, RustcEncodable)]
+pub struct BuildReport {
+  pub problems: Vec<Problem>,
+  pub lookups: Vec<Lookup>,
+  pub duplicates: Vec<Duplicate>,
+  pub known: Vec<Known>,
+}
+
+impl BuildReport {
+  pub fn new(problems: &Vec<ParseError>, notes: &Vec<chinese_note::ChineseNote>) -> BuildReport {
+    let mut rv = BuildReport {
+      problems: Vec::new(),
+      lookups: Vec::new(),
+      duplicates: Vec::new(),
+      known: Vec::new(),
+    };
+    for problem in problems {
+      rv.add_problem(problem);
+    }
+    for note in notes {
+      if note.choice.candidates.len() < 2 && note.choice.rule != chinese_note::Rule::Fallback {
+        continue;
+      }
+      // the note's entry may have been given another entry's definitions (e.g. for erhua words),
+      // so the chosen candidate is recognized by its headword and pinyin
+      let chosen = note.choice.candidates.iter()
+          .find(|c| c.trad == note.ce.trad && c.pinyin == note.ce.pinyin)
+          .unwrap_or(&note.ce);
+      rv.lookups.push(Lookup {
+        word: note.ce.simp.clone(),
+        rule: note.choice.rule.name().to_string(),
+        chosen: cedict::format_entry(chosen),
+        also_shown: note.other_entries.iter().map(cedict::format_entry).collect(),
+        candidates: note.choice.candidates.iter().map(cedict::format_entry).collect(),
+      });
+    }
+    rv
+  }
+
+  pub fn add_problem(&mut self, problem: &ParseError) {
+    // for problems found after the word list was loaded, e.g. with the character notes
+    self.problems.push(Problem {
+      file: problem.file.clone(),
+      line: problem.line,
+      message: problem.message(),
+    });
+  }
+
+  pub fn add_duplicate(&mut self, word: &str) {
+    self.duplicates.push(Duplicate { word: word.to_string() });
+  }
+
+  pub fn add_known(&mut self, word: &str) {
+    self.known.push(Known { word: word.to_string() });
+  }
+
+  pub fn to_json(&self) -> String {
+    json::as_pretty_json(self).to_string()
+  }
+
+  pub fn to_csv(&self) -> String {
+    // one row per problem, per candidate entry of each lookup (with "chosen" in the last column for
+    // the one that was used, and "also shown" for the note's other entries), per duplicate and per
+    // known word
+    let mut wtr = csv::Writer::from_memory();
+    let mut rows : Vec<Vec<String>> = Vec::new();
+    rows.push(vec!("section", "file", "line", "word", "rule", "entry", "detail")
+        .into_iter().map(|s| s.to_string()).collect());
+    for problem in &self.problems {
+      let line = problem.line.map(|l| l.to_string()).unwrap_or(String::new());
+      rows.push(vec!("problem".to_string(), problem.file.clone(), line, String::new(),
+                     String::new(), String::new(), problem.message.clone()));
+    }
+    for lookup in &self.lookups {
+      for candidate in &lookup.candidates {
+        let detail = if *candidate == lookup.chosen {
+          "chosen"
+        } else if lookup.also_shown.contains(candidate) {
+          "also shown"
+        } else {
+          ""
+        };
+        rows.push(vec!("lookup".to_string(), String::new(), String::new(), lookup.word.clone(),
+                       lookup.rule.clone(), candidate.clone(), detail.to_string()));
+      }
+    }
+    for duplicate in &self.duplicates {
+      rows.push(vec!("duplicate".to_string(), String::new(), String::new(),
+                     duplicate.word.clone(), String::new(), String::new(),
+                     "merged".to_string()));
+    }
+    for known in &self.known {
+      rows.push(vec!("known".to_string(), String::new(), String::new(), known.word.clone(),
+                     String::new(), String::new(), "skipped".to_string()));
+    }
+    for row in rows {
+      wtr.write(row.iter()).unwrap();
+    }
+    wtr.as_string().to_string()
+  }
+
+  pub fn write(&self, path: &Path) -> io::Result<()> {
+    // CSV if path ends in .csv, JSON otherwise
+    let contents =
+        if path.to_string_lossy().ends_with(".csv") { self.to_csv() } else { self.to_json() };
+    File::create(path).and_then(|mut f| f.write_all(contents.as_bytes()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cedict;
+  use chinese_note;
+  use parse_error::{ErrorKind, ParseError};
+
+  #[test]
+  fn test_report() {
+    let entries = cedict::parse_entries(concat!(
+        "行 行 [hang2] /row/\n",
+        "行 行 [xing2] /to walk/\n"));
+    let note = chinese_note::ChineseNote {
+      ce: entries[1].clone(),
+      other_entries: vec!(entries[0].clone()),
+      tags: Vec::new(),
+      subdeck: None,
+      choice: chinese_note::EntryChoice {
+        rule: chinese_note::Rule::Preferred,
+        candidates: entries.clone(),
+      },
+    };
+    let problems =
+        vec!(ParseError::new("words.txt", Some(2), ErrorKind::NotInDict("丢".to_string())));
+    let mut report = BuildReport::new(&problems, &vec!(note));
+    report.add_problem(
+        &ParseError::new("cedict.txt", None, ErrorKind::NotInDict("亍".to_string())));
+    report.add_duplicate("等");
+    report.add_known("你");
+    assert_eq!(report.lookups[0].rule, "preferred");
+    assert_eq!(report.lookups[0].chosen, "行 行 [xing2] /to walk/");
+    assert_eq!(report.to_csv(), concat!(
+        "section,file,line,word,rule,entry,detail\n",
+        "problem,words.txt,2,,,,丢 not in dict\n",
+        "problem,cedict.txt,,,,,亍 not in dict\n",
+        "lookup,,,行,preferred,行 行 [hang2] /row/,also shown\n",
+        "lookup,,,行,preferred,行 行 [xing2] /to walk/,chosen\n",
+        "duplicate,,,等,,,merged\n",
+        "known,,,你,,,skipped\n"));
+    assert!(report.to_json().contains("\"message\": \"丢 not in dict\""));
+  }
+}
@@ -1,25 +1,37 @@
+extern crate flate2;
+extern crate rustc_serialize;
+
 use std::ascii::AsciiExt;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use parse_error::{ErrorKind, ParseError};
+use std::path::Path;
+
+// where --cedict looks by default; download the dictionary from
+// https://www.mdbg.net/chinese/dictionary?page=cc-cedict (either the .txt or the .txt.gz works)
+pub const DEFAULT_PATH : &'static str = "src/cedict_1_0_ts_utf-8_mdbg.txt";
 
-#[derive(Clone)]
-#[derive(Eq)]
-#[derive(PartialEq)]
-pub struct Entry<'a> {
-  pub trad: &'a str,
-  pub simp: &'a str,
-  pub pinyin: &'a str,
-  pub tw_pinyin: &'a str,
-  pub defs: Vec<&'a str>,
-  pub clfrs: Vec<Classifier<'a>>,
+// Entries own their strings, so that they can outlive the text they were parsed from (e.g. be
+// returned from a function that reads its own dictionary file) and be serialized.
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Entry {
+  pub trad: String,
+  pub simp: String,
+  pub pinyin: String,
+  pub tw_pinyin: String,
+  // other readings, from "also pr. [...]" definitions
+  pub alt_pinyin: Vec<String>,
+  pub defs: Vec<String>,
+  pub clfrs: Vec<Classifier>,
 }
 
-#[derive(Clone)]
-#[derive(Eq)]
-#[derive(PartialEq)]
-pub struct Classifier<'a> {
-  pub trad: &'a str,
-  pub simp: &'a str,
-  pub pinyin: &'a str,
+#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Classifier {
+  pub trad: String,
+  pub simp: String,
+  pub pinyin: String,
 }
 
 pub struct DictSearchParams<'a> {
@@ -29,12 +41,76 @@ pub struct DictSearchParams<'a> {
   pub pinyin: Option<&'a str>,
 }
 
-fn parse_entry<'a>(entry_str: &'a str) -> Option<Entry<'a>> {
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RefKind {
+  // "variant of X", and its "old", "archaic", "Japanese", etc. forms
+  Variant,
+  // "also written X"
+  AlsoWritten,
+  // "see X" and "see also X"
+  See,
+  // "erhua variant of X", i.e. X with 儿 added
+  Erhua,
+}
+
+// A definition that points to another entry, e.g. "variant of 乾|干[gan1]".
+#[derive(Clone, PartialEq, Debug)]
+pub struct CrossRef {
+  pub kind: RefKind,
+  pub trad: String,
+  pub simp: String,
+  // empty if the reference doesn't say which reading it means
+  pub pinyin: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeadwordSearch {
+  // entries whose simplified or traditional headword starts with the query
+  Prefix,
+  // entries whose simplified or traditional headword contains the query anywhere
+  Contains,
+}
+
+pub fn parse_cross_ref(def: &str) -> Option<CrossRef> {
+  // parses definitions like "old variant of 乾[qian2]" or "see 學生|学生[xue2 sheng5]"; returns None
+  // for definitions that aren't references
+  let kind_re = regex!(r"^(erhua variant of|(?:[a-zA-Z]+ )?variant of|also written|see also|see) ");
+  let target_re = regex!(r"^([^\[\|,;() ]+)(?:\|([^\[,;() ]+))?(?:\[([^\]]+)\])?");
+  let kind_str = match kind_re.captures(def) {
+    Some(cap) => cap.at(1).unwrap(),
+    None => { return None; },
+  };
+  let cap = match target_re.captures(&def[kind_str.len() + 1..]) {
+    Some(cap) => cap,
+    None => { return None; },
+  };
+  let trad = cap.at(1).unwrap();
+  if trad.chars().next().unwrap().is_ascii() {
+    // e.g. "see below"
+    return None;
+  }
+  let kind = match kind_str {
+    "erhua variant of" => RefKind::Erhua,
+    "also written" => RefKind::AlsoWritten,
+    "see" | "see also" => RefKind::See,
+    _ => RefKind::Variant,
+  };
+  Some(CrossRef {
+    kind: kind,
+    trad: trad.to_string(),
+    simp: cap.at(2).unwrap_or(trad).to_string(),
+    pinyin: cap.at(3).unwrap_or("").to_string(),
+  })
+}
+
+fn parse_entry(entry_str: &str, problems: &mut Vec<ErrorKind>) -> Option<Entry> {
+  // problems with parts of the entry that had to be left out are added to problems
   let entry_re = regex!(r"(.+?) (.+?) \[(.+?)\] /(.+)/");
   entry_re.captures(entry_str).map(|cap| {
     let mut defs: Vec<&str> = cap.at(4).unwrap_or("").split("/").collect();
     let mut clfrs = Vec::new();
     let mut tw_pinyin = "";
+    let mut alt_pinyin = Vec::new();
     let mut i = 0;
     while i < defs.len() {
       if defs[i].starts_with("CL:") {
@@ -46,13 +122,13 @@ fn parse_entry<'a>(entry_str: &'a str) -> Option<Entry<'a>> {
             Some(cap) => {
               clfrs.push(
                   Classifier {
-                      trad: cap.at(1).unwrap_or(""),
-                      simp: cap.at(2).unwrap_or(cap.at(1).unwrap_or("")),
-                      pinyin: cap.at(3).unwrap_or(""),
+                      trad: cap.at(1).unwrap_or("").to_string(),
+                      simp: cap.at(2).unwrap_or(cap.at(1).unwrap_or("")).to_string(),
+                      pinyin: cap.at(3).unwrap_or("").to_string(),
                   }
               );
             },
-            _ => { println!("Couldn't parse {} as a classifier", clfr_str) },
+            _ => { problems.push(ErrorKind::MalformedClassifier(clfr_str.to_string())); },
           }
         }
       } else if defs[i].starts_with("Taiwan pr. ") {
@@ -68,31 +144,146 @@ fn parse_entry<'a>(entry_str: &'a str) -> Option<Entry<'a>> {
           }
         }
       } else if defs[i].starts_with("also pr. ") {
-        // TODO: do we want to include this information elsewhere on the card?
-        defs.remove(i);
+        // e.g. "also pr. [ji1]" or "also pr. [da1] and [ta1]"
+        let alt_pinyin_re = regex!(r"\[([a-zA-Z0-9: ]+)\]");
+        for cap in alt_pinyin_re.captures_iter(defs.remove(i)) {
+          alt_pinyin.push(cap.at(1).unwrap().to_string());
+        }
       } else {
         i += 1;
       }
     }
-    Entry{trad: cap.at(1).unwrap_or(""),
-          simp: cap.at(2).unwrap_or(""),
-          pinyin: cap.at(3).unwrap_or(""),
-          tw_pinyin: tw_pinyin,
-          defs: defs,
+    Entry{trad: cap.at(1).unwrap_or("").to_string(),
+          simp: cap.at(2).unwrap_or("").to_string(),
+          pinyin: cap.at(3).unwrap_or("").to_string(),
+          tw_pinyin: tw_pinyin.to_string(),
+          alt_pinyin: alt_pinyin,
+          defs: defs.iter().map(|d| d.to_string()).collect(),
           clfrs: clfrs}
   })
 }
 
-fn build_index<'a, 'b, F>(entries: &'a Vec<Entry<'b>>, get_key: F)
-    -> HashMap<String, Vec<usize>>
-    where F : Fn(&Entry<'b>) -> &'b str {
+fn build_index<F>(entries: &Vec<Entry>, get_key: F) -> HashMap<String, Vec<usize>>
+    where F : Fn(&Entry) -> String {
   let mut rv = HashMap::new();
   for i in 0..entries.len() {
     let key = get_key(&entries[i]);
-    if !rv.contains_key(key) {
-      rv.insert(key.to_string(), Vec::new());
+    if !rv.contains_key(&key) {
+      rv.insert(key.clone(), Vec::new());
+    }
+    rv.get_mut(&key).unwrap().push(i);
+  }
+  rv
+}
+
+fn def_tokens(def: &str) -> Vec<String> {
+  // the lowercase words of a definition (or English query)
+  def.to_lowercase()
+      .split(|c: char| !c.is_alphanumeric())
+      .filter(|t| !t.is_empty())
+      .map(|t| t.to_string())
+      .collect()
+}
+
+fn gloss_key(def: &str) -> Vec<String> {
+  // the words of a definition, minus parenthesized remarks and a leading "to", so that "borrow"
+  // and "to borrow" both match "to borrow (money)"
+  let mut depth = 0;
+  let mut stripped = String::new();
+  for c in def.chars() {
+    match c {
+      '(' => { depth += 1; },
+      ')' if depth > 0 => { depth -= 1; },
+      _ if depth == 0 => stripped.push(c),
+      _ => (),
+    }
+  }
+  let mut rv = def_tokens(&stripped);
+  if rv.len() > 1 && rv[0] == "to" {
+    rv.remove(0);
+  }
+  rv
+}
+
+fn english_match_rank(def: &str, query: &Vec<String>) -> Option<usize> {
+  // 0 if def is exactly query (see gloss_key), 1 if it contains query as whole words, 2 if it
+  // contains query as a substring, or None if it doesn't contain query at all
+  if gloss_key(def) == *query {
+    return Some(0);
+  }
+  let tokens = def_tokens(def);
+  if tokens.windows(query.len()).any(|w| w == &query[..]) {
+    return Some(1);
+  }
+  if tokens.connect(" ").contains(&query.connect(" ")) {
+    return Some(2);
+  }
+  None
+}
+
+// Pinyin broken down for tone-insensitive search: the letters alone, lowercase and without spaces
+// or punctuation, with ü written as v (e.g. "nvren" for "nu:3 ren2" or "nǚ rén"), plus the tones
+// that were given, each with the index of the letter it follows (for tone marks, the marked vowel).
+struct SplitPinyin {
+  letters: String,
+  tones: Vec<(usize, char)>,
+}
+
+fn tone_mark(c: char) -> Option<(char, char)> {
+  // the unmarked vowel and tone number of a vowel with a tone mark
+  let data = [
+      ['ā', 'á', 'ǎ', 'à', 'a'],
+      ['ē', 'é', 'ě', 'è', 'e'],
+      ['ī', 'í', 'ǐ', 'ì', 'i'],
+      ['ō', 'ó', 'ǒ', 'ò', 'o'],
+      ['ū', 'ú', 'ǔ', 'ù', 'u'],
+      ['ǖ', 'ǘ', 'ǚ', 'ǜ', 'v'],
+  ];
+  for row in data.iter() {
+    for tone in 0..4 {
+      if row[tone] == c {
+        return Some((row[4], ['1', '2', '3', '4'][tone]));
+      }
+    }
+  }
+  None
+}
+
+fn split_pinyin(pinyin: &str) -> SplitPinyin {
+  let mut rv = SplitPinyin { letters: String::new(), tones: Vec::new() };
+  for c in pinyin.to_lowercase().replace("u:", "v").replace("ü", "v").chars() {
+    match tone_mark(c) {
+      Some((vowel, tone)) => {
+        rv.letters.push(vowel);
+        rv.tones.push((rv.letters.len() - 1, tone));
+      },
+      None if c.is_digit(10) && !rv.letters.is_empty() => {
+        rv.tones.push((rv.letters.len() - 1, c));
+      },
+      None if c.is_alphabetic() => rv.letters.push(c),
+      None => (),
     }
-    rv.get_mut(key).unwrap().push(i);
+  }
+  rv
+}
+
+fn toneless(pinyin: &str) -> String {
+  split_pinyin(pinyin).letters
+}
+
+fn tone_mismatches(entry_pinyin: &str, query: &SplitPinyin) -> usize {
+  // how many of entry_pinyin's syllables have a tone in query that differs from their own;
+  // entry_pinyin must have the same letters as query
+  let mut rv = 0;
+  let mut start = 0;
+  for syllable in entry_pinyin.split(' ') {
+    let syllable = split_pinyin(syllable);
+    let end = start + syllable.letters.len();
+    let tone = syllable.tones.last().map(|&(_, t)| t).unwrap_or('5');
+    if query.tones.iter().any(|&(i, t)| start <= i && i < end && t != tone) {
+      rv += 1;
+    }
+    start = end;
   }
   rv
 }
@@ -125,41 +316,217 @@ fn entry_matches(entry: &Entry, params: &DictSearchParams) -> bool {
   true
 }
 
-pub struct Dict<'a> {
-  entries: Vec<Entry<'a>>,
+pub fn read_cedict(path: &Path) -> Result<String, String> {
+  // reads a CC-CEDICT file, which may be gzipped (if its name ends in .gz), so that it can be
+  // passed to Dict::new
+  let file = try!(File::open(path).map_err(|e| {
+    format!("Could not open CC-CEDICT file {}: {} (see --cedict)", path.display(), e)
+  }));
+  let mut rv = String::new();
+  let result = if path.extension().map(|e| e == "gz").unwrap_or(false) {
+    flate2::read::GzDecoder::new(file).and_then(|mut f| f.read_to_string(&mut rv))
+  } else {
+    let mut file = file;
+    file.read_to_string(&mut rv)
+  };
+  try!(result.map_err(|e| {
+    format!("Could not read CC-CEDICT file {}; it must be UTF-8 text, optionally gzipped: {}",
+            path.display(), e)
+  }));
+  if !rv.split("\n").any(|line| parse_entry(line, &mut Vec::new()).is_some()) {
+    return Err(format!(
+        concat!("{} doesn't look like a CC-CEDICT file; expected lines like ",
+                "\"中國 中国 [Zhong1 guo2] /China/\""),
+        path.display()));
+  }
+  Ok(rv)
+}
+
+pub struct Dict {
+  entries: Vec<Entry>,
   trad_idx: HashMap<String, Vec<usize>>,
   simp_idx: HashMap<String, Vec<usize>>,
   pinyin_idx: HashMap<String, Vec<usize>>,
+  // keyed by pinyin without tones or spaces, e.g. "nihao"
+  toneless_idx: HashMap<String, Vec<usize>>,
+  // for search_english; only built the first time it's needed
+  english_idx: RefCell<Option<EnglishIndex>>,
+  // for search_headwords; only built the first time it's needed (see build_headword_suffixes)
+  headword_suffixes: RefCell<Option<Vec<HeadwordSuffix>>>,
+}
+
+// The part of entries[entry]'s headword (trad if .trad, simp otherwise) that starts at byte .start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeadwordSuffix {
+  pub entry: usize,
+  pub trad: bool,
+  pub start: usize,
+}
+
+fn headword_suffix<'a>(entries: &'a Vec<Entry>, suffix: &HeadwordSuffix) -> &'a str {
+  let entry = &entries[suffix.entry];
+  &(if suffix.trad { &entry.trad } else { &entry.simp })[suffix.start..]
+}
+
+pub fn build_headword_suffixes(entries: &Vec<Entry>) -> Vec<HeadwordSuffix> {
+  // every suffix of every simplified and traditional headword, sorted, for prefix and substring
+  // searches
+  let mut rv = Vec::new();
+  for i in 0..entries.len() {
+    let entry = &entries[i];
+    for &trad in &[false, true] {
+      if trad && entry.trad == entry.simp {
+        continue;
+      }
+      let headword = if trad { &entry.trad } else { &entry.simp };
+      for (start, _) in headword.char_indices() {
+        rv.push(HeadwordSuffix { entry: i, trad: trad, start: start });
+      }
+    }
+  }
+  rv.sort_by(|a, b| headword_suffix(entries, a).cmp(headword_suffix(entries, b)));
+  rv
+}
+
+// The words that appear in the entries' definitions, for search_english.
+#[derive(Debug, PartialEq)]
+pub struct EnglishIndex {
+  // each word (see def_tokens), sorted, with the entries that have it in their definitions
+  pub tokens: Vec<(String, Vec<usize>)>,
+  // every suffix of every word, as (index into tokens, byte offset), sorted by the suffix, so that
+  // the words containing a query word can be found without scanning them all
+  pub token_suffixes: Vec<(usize, usize)>,
+}
+
+impl EnglishIndex {
+  pub fn new(entries: &Vec<Entry>) -> EnglishIndex {
+    let mut by_token : HashMap<String, Vec<usize>> = HashMap::new();
+    for i in 0..entries.len() {
+      let tokens : HashSet<String> =
+          entries[i].defs.iter().flat_map(|d| def_tokens(d).into_iter()).collect();
+      for token in tokens {
+        by_token.entry(token).or_insert(Vec::new()).push(i);
+      }
+    }
+    let mut tokens : Vec<(String, Vec<usize>)> = by_token.into_iter().collect();
+    tokens.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut token_suffixes = Vec::new();
+    for (i, &(ref token, _)) in tokens.iter().enumerate() {
+      for (start, _) in token.char_indices() {
+        token_suffixes.push((i, start));
+      }
+    }
+    {
+      let tokens = &tokens;
+      token_suffixes.sort_by(|&(a, a_start), &(b, b_start)| {
+        tokens[a].0[a_start..].cmp(&tokens[b].0[b_start..])
+      });
+    }
+    EnglishIndex { tokens: tokens, token_suffixes: token_suffixes }
+  }
+
+  fn token_suffix(&self, i: usize) -> &str {
+    let (token, start) = self.token_suffixes[i];
+    &self.tokens[token].0[start..]
+  }
+
+  fn entries_with(&self, word: &str) -> HashSet<usize> {
+    // the entries with a word in their definitions that contains word; the suffixes starting with
+    // word are next to each other in token_suffixes, and the word itself (if it appears on its
+    // own) is the one that starts at 0
+    let (mut lo, mut hi) = (0, self.token_suffixes.len());
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      if self.token_suffix(mid) < word {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    let mut rv = HashSet::new();
+    for i in lo..self.token_suffixes.len() {
+      if !self.token_suffix(i).starts_with(word) {
+        break;
+      }
+      rv.extend(self.tokens[self.token_suffixes[i].0].1.iter().cloned());
+    }
+    rv
+  }
 }
 
-impl<'a> Dict<'a> {
-  pub fn new() -> Dict<'static> {
-    Dict::new_with_extra_entries("")
+pub fn format_entry(entry: &Entry) -> String {
+  // the entry as a CC-CEDICT line, minus the classifiers and other pronunciations
+  format!("{} {} [{}] /{}/", entry.trad, entry.simp, entry.pinyin, entry.defs.connect("/"))
+}
+
+pub fn parse_entries_from(text: &str, path: &str) -> (Vec<Entry>, Vec<ParseError>) {
+  // parses the contents of the CC-CEDICT file at path (which is only used in the returned
+  // problems); blank lines and comments are skipped, as are lines that aren't entries, which are
+  // reported
+  let mut entries = Vec::new();
+  let mut errors = Vec::new();
+  for (i, line) in text.split("\n").enumerate() {
+    if line.trim() == "" || line.starts_with("#") {
+      continue;
+    }
+    let mut problems = Vec::new();
+    match parse_entry(line, &mut problems) {
+      Some(entry) => entries.push(entry),
+      None => problems.push(ErrorKind::MalformedEntry(line.to_string())),
+    }
+    errors.extend(problems.into_iter().map(|kind| ParseError::new(path, Some(i + 1), kind)));
   }
+  (entries, errors)
+}
 
-  pub fn new_with_extra_entries<'b>(extra: &'b str) -> Dict<'b> {
+pub fn parse_entries(text: &str) -> Vec<Entry> {
+  // like parse_entries_from, for text that's known to be good (e.g. in tests)
+  parse_entries_from(text, "").0
+}
+
+impl Dict {
+  pub fn new(cedict: &str) -> Dict {
+    // cedict is the contents of a CC-CEDICT file, as returned by read_cedict
+    Dict::from_entries(parse_entries(cedict))
+  }
+
+  pub fn new_with_extra_entries(cedict: &Vec<Entry>, extra: Vec<Entry>) -> Dict {
+    // cedict is the parsed CC-CEDICT (see parse_entries_from and cedict_index::load_entries);
+    // entries from "extra" will appear before entries from the main dict
+    let mut entries = extra;
+    entries.extend(cedict.iter().cloned());
+    Dict::from_entries(entries)
+  }
+
+  pub fn from_entries(entries: Vec<Entry>) -> Dict {
     let mut rv = Dict {
-      entries: Vec::new(),
+      entries: entries,
       trad_idx: HashMap::new(),
       simp_idx: HashMap::new(),
       pinyin_idx: HashMap::new(),
+      toneless_idx: HashMap::new(),
+      english_idx: RefCell::new(None),
+      headword_suffixes: RefCell::new(None),
     };
-    // entries from "extra" will appear before entries from the main dict
-    for line in extra.split("\n").chain(include_str!("cedict_1_0_ts_utf-8_mdbg.txt").split("\n")) {
-      match parse_entry(line) {
-        Some(ent) => {
-          rv.entries.push(ent);
-        },
-        None => (),
-      }
-    }
-    rv.trad_idx = build_index(&rv.entries, |ent| ent.trad);
-    rv.simp_idx = build_index(&rv.entries, |ent| ent.simp);
-    rv.pinyin_idx = build_index(&rv.entries, |ent| &ent.pinyin.to_ascii_lowercase());
+    rv.trad_idx = build_index(&rv.entries, |ent| ent.trad.clone());
+    rv.simp_idx = build_index(&rv.entries, |ent| ent.simp.clone());
+    rv.pinyin_idx = build_index(&rv.entries, |ent| ent.pinyin.to_ascii_lowercase());
+    rv.toneless_idx = build_index(&rv.entries, |ent| toneless(&ent.pinyin));
+    rv
+  }
+
+  pub fn with_search_tables(entries: Vec<Entry>, headword_suffixes: Vec<HeadwordSuffix>,
+                            english_idx: EnglishIndex) -> Dict {
+    // like from_entries, with the tables for search_headwords and search_english already built
+    // (by build_headword_suffixes and EnglishIndex::new) for the same entries, e.g. by
+    // cedict_index::load_dict
+    let rv = Dict::from_entries(entries);
+    *rv.headword_suffixes.borrow_mut() = Some(headword_suffixes);
+    *rv.english_idx.borrow_mut() = Some(english_idx);
     rv
   }
 
-  pub fn search(&self, params: DictSearchParams) -> Vec<Entry<'a>> {
+  pub fn search(&self, params: DictSearchParams) -> Vec<Entry> {
     // TODO: figure out if this can be expressed more succinctly
     let pinyin_lower_string = match params.pinyin {
       Some(p) => p.to_ascii_lowercase(),
@@ -189,9 +556,149 @@ impl<'a> Dict<'a> {
     rv
   }
 
-  pub fn search_simp(&self, simp: &str) -> Vec<Entry<'a>> {
+  pub fn search_simp(&self, simp: &str) -> Vec<Entry> {
     self.search(DictSearchParams{simp: Some(simp), trad: None, pinyin: None})
   }
+
+  pub fn search_pinyin(&self, query: &str) -> Vec<Entry> {
+    // finds entries whose pinyin matches query when tones are ignored. query may use tone numbers
+    // ("ni3 hao3"), tone marks ("nǐ hǎo") or no tones at all, with or without spaces ("nihao").
+    // Entries whose tones agree with the ones given in query come first, then those with the
+    // fewest wrong tones; ties go to common words over proper nouns, then to dictionary order.
+    let query = split_pinyin(query);
+    let candidate_idxs = match self.toneless_idx.get(&query.letters) {
+      Some(c) => c,
+      None => { return Vec::new(); },
+    };
+    let mut ranked = Vec::new();
+    for &i in candidate_idxs {
+      let entry = &self.entries[i];
+      let proper_noun = entry.pinyin.chars().next().map_or(false, |c| c.is_uppercase());
+      ranked.push((tone_mismatches(&entry.pinyin, &query), proper_noun, i));
+    }
+    ranked.sort();
+    ranked.iter().map(|&(_, _, i)| self.entries[i].clone()).collect()
+  }
+
+  pub fn resolve(&self, cross_ref: &CrossRef) -> Vec<Entry> {
+    // the entries cross_ref points to: those with its headword and, if it gives one, its pinyin
+    let params = DictSearchParams{simp: None, trad: Some(&cross_ref.trad), pinyin: None};
+    let rv : Vec<Entry> = self.search(params).into_iter()
+        .filter(|e| e.simp == cross_ref.simp)
+        .collect();
+    if cross_ref.pinyin == "" {
+      return rv;
+    }
+    let pinyin = cross_ref.pinyin.to_ascii_lowercase();
+    let with_pinyin : Vec<Entry> =
+        rv.iter().filter(|e| e.pinyin.to_ascii_lowercase() == pinyin).cloned().collect();
+    // references occasionally get the pinyin slightly wrong, so fall back to the headword alone
+    if with_pinyin.is_empty() { rv } else { with_pinyin }
+  }
+
+  pub fn follow_refs(&self, entry: &Entry) -> Option<Entry> {
+    // for an entry that's just a variant or erhua form of another word (i.e. whose first
+    // definition is a reference to it), returns the entry for that word, following chains of such
+    // references. Returns None if entry has definitions of its own, or if the reference can't be
+    // resolved or leads in a circle.
+    let mut seen = HashSet::new();
+    let mut current = entry.clone();
+    loop {
+      let cross_ref = match current.defs.get(0).and_then(|d| parse_cross_ref(d)) {
+        Some(r) => {
+          if r.kind != RefKind::Variant && r.kind != RefKind::Erhua {
+            break;
+          }
+          r
+        },
+        None => { break; },
+      };
+      if !seen.insert((current.trad.clone(), current.pinyin.clone())) {
+        return None;
+      }
+      current = match self.resolve(&cross_ref).into_iter().next() {
+        Some(target) => target,
+        None => { return None; },
+      };
+    }
+    if current == *entry { None } else { Some(current) }
+  }
+
+  pub fn search_headwords(&self, query: &str, how: HeadwordSearch) -> Vec<Entry> {
+    // finds entries whose simplified or traditional headword starts with (or, for Contains,
+    // contains) query, e.g. 学习 and 学生 for the prefix 学. Shorter headwords come first, then
+    // dictionary order; see hsk::sort_by_level to order by HSK level instead.
+    if query.is_empty() {
+      return Vec::new();
+    }
+    if self.headword_suffixes.borrow().is_none() {
+      *self.headword_suffixes.borrow_mut() = Some(build_headword_suffixes(&self.entries));
+    }
+    let headword_suffixes = self.headword_suffixes.borrow();
+    let headword_suffixes = headword_suffixes.as_ref().unwrap();
+    // the first suffix that isn't less than query; every suffix starting with query follows it
+    let (mut lo, mut hi) = (0, headword_suffixes.len());
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      if headword_suffix(&self.entries, &headword_suffixes[mid]) < query {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    let mut idxs = HashSet::new();
+    for suffix in &headword_suffixes[lo..] {
+      if !headword_suffix(&self.entries, suffix).starts_with(query) {
+        break;
+      }
+      if how == HeadwordSearch::Contains || suffix.start == 0 {
+        idxs.insert(suffix.entry);
+      }
+    }
+    let mut ranked : Vec<(usize, usize)> =
+        idxs.into_iter().map(|i| (self.entries[i].simp.chars().count(), i)).collect();
+    ranked.sort();
+    ranked.iter().map(|&(_, i)| self.entries[i].clone()).collect()
+  }
+
+  pub fn search_english(&self, query: &str) -> Vec<Entry> {
+    // finds entries with a definition that contains query, e.g. "to borrow". Entries with a
+    // definition that is exactly query (ignoring case, parenthesized remarks and a leading "to")
+    // come first, then ones where it appears as whole words, then ones where it's only part of a
+    // word (e.g. "borrower"). Ties go to entries with shorter matching definitions, then to
+    // dictionary order.
+    let query = gloss_key(query);
+    if query.is_empty() {
+      return Vec::new();
+    }
+    if self.english_idx.borrow().is_none() {
+      *self.english_idx.borrow_mut() = Some(EnglishIndex::new(&self.entries));
+    }
+    let english_idx = self.english_idx.borrow();
+    let english_idx = english_idx.as_ref().unwrap();
+    // entries that have, for each word of query, a word in their definitions that contains it
+    let mut candidates : Option<HashSet<usize>> = None;
+    for word in &query {
+      let with_word = english_idx.entries_with(word);
+      candidates = Some(match candidates {
+        Some(c) => c.intersection(&with_word).cloned().collect(),
+        None => with_word,
+      });
+    }
+
+    let mut ranked = Vec::new();
+    for i in candidates.unwrap() {
+      let best = self.entries[i].defs.iter()
+          .filter_map(|d| english_match_rank(d, &query).map(|rank| (rank, def_tokens(d).len())))
+          .min();
+      match best {
+        Some((rank, len)) => ranked.push((rank, len, i)),
+        None => (),
+      }
+    }
+    ranked.sort();
+    ranked.iter().map(|&(_, _, i)| self.entries[i].clone()).collect()
+  }
 }
 
 // TODO: can we make this only pub for testing?
@@ -240,6 +747,136 @@ pub fn pinyin_to_ascii(pinyin: &str) -> String {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use parse_error::{ErrorKind, ParseError};
+
+  #[test]
+  fn test_entries_outlive_source() {
+    let entry = {
+      let text = "中國 中国 [Zhong1 guo2] /China/CL:個|个[ge4]/\n".to_string();
+      Dict::new(&text).search_simp("中国").pop().unwrap()
+    };
+    assert_eq!(entry.trad, "中國");
+    assert_eq!(entry.defs, vec!("China"));
+    assert_eq!(entry.clfrs[0].simp, "个");
+  }
+
+  #[test]
+  fn test_parse_entries_from() {
+    let (entries, errors) = parse_entries_from(
+        concat!("# comment\n",
+                "\n",
+                "個 个 [ge4] /individual/\n",
+                "not an entry\n",
+                "張 张 [zhang1] /sheet/CL:枚[mei2],bad/\n"),
+        "extra.txt");
+    assert_eq!(entries.len(), 2);
+    assert_eq!(errors, vec!(
+        ParseError::new(
+            "extra.txt", Some(4), ErrorKind::MalformedEntry("not an entry".to_string())),
+        ParseError::new(
+            "extra.txt", Some(5), ErrorKind::MalformedClassifier("bad".to_string()))));
+  }
+
+  #[test]
+  fn test_alt_pinyin() {
+    let entry = parse_entries("落 落 [luo4] /to fall/also pr. [la4] and [lao4]/\n").pop().unwrap();
+    assert_eq!(entry.alt_pinyin, vec!("la4", "lao4"));
+    assert_eq!(entry.defs, vec!("to fall"));
+  }
+
+  #[test]
+  fn test_search_pinyin() {
+    let dict = Dict::new(concat!(
+        "你好 你好 [ni3 hao3] /hello/\n",
+        "媽 妈 [ma1] /mom/\n",
+        "馬 马 [Ma3] /surname Ma/\n",
+        "馬 马 [ma3] /horse/\n",
+        "嗎 吗 [ma5] /(question particle)/\n",
+        "女人 女人 [nu:3 ren2] /woman/\n"));
+    let simps = |query| dict.search_pinyin(query).iter()
+        .map(|e| e.simp.clone() + " " + &e.pinyin).collect::<Vec<_>>();
+    assert_eq!(simps("ma3"), vec!("马 ma3", "马 Ma3", "妈 ma1", "吗 ma5"));
+    assert_eq!(simps("nihao"), vec!("你好 ni3 hao3"));
+    assert_eq!(simps("nǐ hǎo"), vec!("你好 ni3 hao3"));
+    assert_eq!(simps("ni3hao"), vec!("你好 ni3 hao3"));
+    assert_eq!(simps("nǚrén"), vec!("女人 nu:3 ren2"));
+    assert_eq!(simps("nihaoma"), Vec::<String>::new());
+  }
+
+  #[test]
+  fn test_search_english() {
+    let dict = Dict::new(concat!(
+        "借錢 借钱 [jie4 qian2] /to borrow money/\n",
+        "借款人 借款人 [jie4 kuan3 ren2] /borrower/\n",
+        "借 借 [jie4] /to lend/to borrow (sth)/\n",
+        "借 借 [jie4] /variant of 藉[jie4]/\n",
+        "借用 借用 [jie4 yong4] /to borrow/\n"));
+    // the index is only built once it's needed
+    assert!(dict.english_idx.borrow().is_none());
+    let simps = |query| dict.search_english(query).iter()
+        .map(|e| e.simp.clone()).collect::<Vec<_>>();
+    assert_eq!(simps("to borrow"), vec!("借用", "借", "借钱", "借款人"));
+    assert_eq!(simps("Borrow"), vec!("借用", "借", "借钱", "借款人"));
+    assert_eq!(simps("borrow money"), vec!("借钱"));
+    assert_eq!(simps("money borrow"), Vec::<String>::new());
+    assert_eq!(simps("orrowe"), vec!("借款人"));
+    assert_eq!(simps("lend"), vec!("借"));
+  }
+
+  #[test]
+  fn test_search_headwords() {
+    let dict = Dict::new(concat!(
+        "學生 学生 [xue2 sheng5] /student/\n",
+        "學 学 [xue2] /to learn/\n",
+        "大學 大学 [da4 xue2] /university/\n",
+        "學習 学习 [xue2 xi2] /to learn/\n",
+        "大 大 [da4] /big/\n"));
+    // the suffix table is only built once it's needed
+    assert!(dict.headword_suffixes.borrow().is_none());
+    let simps = |query, how| dict.search_headwords(query, how).iter()
+        .map(|e| e.simp.clone()).collect::<Vec<_>>();
+    assert_eq!(simps("学", HeadwordSearch::Prefix), vec!("学", "学生", "学习"));
+    assert_eq!(simps("學", HeadwordSearch::Prefix), vec!("学", "学生", "学习"));
+    assert_eq!(simps("学", HeadwordSearch::Contains), vec!("学", "学生", "大学", "学习"));
+    assert_eq!(simps("学习", HeadwordSearch::Contains), vec!("学习"));
+    assert_eq!(simps("小", HeadwordSearch::Contains), Vec::<String>::new());
+  }
+
+  #[test]
+  fn test_parse_cross_ref() {
+    assert_eq!(parse_cross_ref("old variant of 乾|干[gan1]"), Some(CrossRef {
+      kind: RefKind::Variant,
+      trad: "乾".to_string(),
+      simp: "干".to_string(),
+      pinyin: "gan1".to_string(),
+    }));
+    assert_eq!(parse_cross_ref("also written 盡快|尽快"), Some(CrossRef {
+      kind: RefKind::AlsoWritten,
+      trad: "盡快".to_string(),
+      simp: "尽快".to_string(),
+      pinyin: String::new(),
+    }));
+    assert_eq!(parse_cross_ref("erhua variant of 玩[wan2]").unwrap().kind, RefKind::Erhua);
+    assert_eq!(parse_cross_ref("see also 學|学[xue2]").unwrap().kind, RefKind::See);
+    assert_eq!(parse_cross_ref("see below"), None);
+    assert_eq!(parse_cross_ref("to play"), None);
+  }
+
+  #[test]
+  fn test_follow_refs() {
+    let dict = Dict::new(concat!(
+        "玩兒 玩儿 [wan2 r5] /erhua variant of 玩[wan2]/\n",
+        "玩 玩 [wan2] /to play/\n",
+        "翫 玩 [wan2] /old variant of 玩[wan2]/\n",
+        "甲 甲 [jia3] /variant of 乙[yi3]/\n",
+        "乙 乙 [yi3] /variant of 甲[jia3]/\n"));
+    let erhua = &dict.search_simp("玩儿")[0];
+    assert_eq!(dict.follow_refs(erhua).unwrap().defs, vec!("to play"));
+    let play = &dict.search_simp("玩")[0];
+    assert_eq!(dict.follow_refs(play), None);
+    let circular = &dict.search_simp("甲")[0];
+    assert_eq!(dict.follow_refs(circular), None);
+  }
 
   #[test]
   fn test_pinyin_to_ascii() {
@@ -0,0 +1,453 @@
+use cedict;
+use parse_error::{ErrorKind, ParseError};
+use std;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Parsing the full CC-CEDICT with regexes takes a while, so the parsed entries are cached in a
+// compact binary file next to the dictionary. The file starts with MAGIC and the size and
+// modification time of the dictionary file it was built from, so it's rebuilt automatically
+// whenever the dictionary (or the format below) changes, without having to read the dictionary
+// otherwise. Everything after that is a u32 entry count followed by the entries, then a u32 count
+// of the problems found while parsing followed by the problems, and finally the tables that
+// Dict::search_headwords and Dict::search_english use, which take a while to sort. Numbers are
+// little-endian u32s, and strings are a u32 byte length followed by UTF-8 bytes.
+
+const MAGIC : &'static [u8] = b"hsk_flashcards cedict index v4\n";
+
+fn source_key(path: &Path) -> Option<[u8; 20]> {
+  // the size of the file at path, and the seconds and nanoseconds of its modification time
+  let metadata = match std::fs::metadata(path) {
+    Ok(metadata) => metadata,
+    Err(_) => { return None; },
+  };
+  let modified = match metadata.modified().ok()
+      .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()) {
+    Some(modified) => modified,
+    None => { return None; },
+  };
+  let mut key = Vec::new();
+  for &n in &[metadata.len(), modified.as_secs(), modified.subsec_nanos() as u64] {
+    for i in 0..8 {
+      key.push((n >> (8 * i)) as u8);
+    }
+  }
+  let mut rv = [0; 20];
+  for i in 0..20 {
+    rv[i] = key[i];
+  }
+  Some(rv)
+}
+
+fn write_u32(out: &mut Vec<u8>, n: u32) {
+  for i in 0..4 {
+    out.push((n >> (8 * i)) as u8);
+  }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+  write_u32(out, s.len() as u32);
+  out.extend(s.as_bytes().iter().cloned());
+}
+
+fn encode_error(out: &mut Vec<u8>, error: &ParseError) {
+  // the line (0 if there isn't one), a tag for the kind and the kind's text; the file isn't
+  // stored, since it's whatever path the dictionary is read from
+  write_u32(out, error.line.unwrap_or(0) as u32);
+  let (tag, text) = match error.kind {
+    ErrorKind::MalformedEntry(ref t) => (0, t),
+    ErrorKind::MalformedClassifier(ref t) => (1, t),
+    ErrorKind::MalformedWordListLine(ref t) => (2, t),
+    ErrorKind::NotInDict(ref t) => (3, t),
+    ErrorKind::MalformedPreferredEntry(ref t) => (4, t),
+    ErrorKind::UnusedPreferredEntry(ref t) => (5, t),
+    ErrorKind::UnsatisfiablePreferredEntry(ref t) => (6, t),
+    ErrorKind::ConflictingPreferredEntry(ref t) => (7, t),
+    ErrorKind::ShadowingEntry(ref t) => (8, t),
+    ErrorKind::MalformedSentence(ref t) => (9, t),
+  };
+  write_u32(out, tag);
+  write_str(out, text);
+}
+
+fn encode(key: &[u8; 20], entries: &Vec<cedict::Entry>, errors: &Vec<ParseError>,
+          tables: &SearchTables) -> Vec<u8> {
+  let mut rv = MAGIC.to_vec();
+  rv.extend(key.iter().cloned());
+  write_u32(&mut rv, entries.len() as u32);
+  for entry in entries {
+    write_str(&mut rv, &entry.trad);
+    write_str(&mut rv, &entry.simp);
+    write_str(&mut rv, &entry.pinyin);
+    write_str(&mut rv, &entry.tw_pinyin);
+    write_u32(&mut rv, entry.alt_pinyin.len() as u32);
+    for alt_pinyin in &entry.alt_pinyin {
+      write_str(&mut rv, alt_pinyin);
+    }
+    write_u32(&mut rv, entry.defs.len() as u32);
+    for def in &entry.defs {
+      write_str(&mut rv, def);
+    }
+    write_u32(&mut rv, entry.clfrs.len() as u32);
+    for clfr in &entry.clfrs {
+      write_str(&mut rv, &clfr.trad);
+      write_str(&mut rv, &clfr.simp);
+      write_str(&mut rv, &clfr.pinyin);
+    }
+  }
+  write_u32(&mut rv, errors.len() as u32);
+  for error in errors {
+    encode_error(&mut rv, error);
+  }
+  write_u32(&mut rv, tables.headword_suffixes.len() as u32);
+  for suffix in &tables.headword_suffixes {
+    write_u32(&mut rv, suffix.entry as u32);
+    write_u32(&mut rv, suffix.trad as u32);
+    write_u32(&mut rv, suffix.start as u32);
+  }
+  write_u32(&mut rv, tables.english_idx.tokens.len() as u32);
+  for &(ref token, ref idxs) in &tables.english_idx.tokens {
+    write_str(&mut rv, token);
+    write_u32(&mut rv, idxs.len() as u32);
+    for &i in idxs {
+      write_u32(&mut rv, i as u32);
+    }
+  }
+  write_u32(&mut rv, tables.english_idx.token_suffixes.len() as u32);
+  for &(token, start) in &tables.english_idx.token_suffixes {
+    write_u32(&mut rv, token as u32);
+    write_u32(&mut rv, start as u32);
+  }
+  rv
+}
+
+// The sorted tables that are stored after the entries; see cedict::Dict::with_search_tables.
+#[derive(Debug, PartialEq)]
+struct SearchTables {
+  headword_suffixes: Vec<cedict::HeadwordSuffix>,
+  english_idx: cedict::EnglishIndex,
+}
+
+// Reads values back out of an encoded index; every method returns None if the data is truncated
+// or otherwise corrupt.
+struct Decoder<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+  fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+    if self.data.len() - self.pos < len {
+      return None;
+    }
+    self.pos += len;
+    Some(&self.data[self.pos - len..self.pos])
+  }
+
+  fn u32(&mut self) -> Option<u32> {
+    self.bytes(4).map(|b| {
+      (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    })
+  }
+
+  fn string(&mut self) -> Option<String> {
+    let len = match self.u32() {
+      Some(len) => len as usize,
+      None => { return None; },
+    };
+    self.bytes(len).and_then(|b| String::from_utf8(b.to_vec()).ok())
+  }
+
+  fn strings(&mut self, n: usize) -> Option<Vec<String>> {
+    let mut rv = Vec::new();
+    for _ in 0..n {
+      match self.string() {
+        Some(s) => rv.push(s),
+        None => { return None; },
+      }
+    }
+    Some(rv)
+  }
+
+  fn entry(&mut self) -> Option<cedict::Entry> {
+    let mut strs = match self.strings(4) {
+      Some(strs) => strs.into_iter(),
+      None => { return None; },
+    };
+    let (trad, simp, pinyin, tw_pinyin) =
+        (strs.next().unwrap(), strs.next().unwrap(), strs.next().unwrap(), strs.next().unwrap());
+    let alt_pinyin = match self.u32().and_then(|n| self.strings(n as usize)) {
+      Some(alt_pinyin) => alt_pinyin,
+      None => { return None; },
+    };
+    let defs = match self.u32().and_then(|n| self.strings(n as usize)) {
+      Some(defs) => defs,
+      None => { return None; },
+    };
+    let num_clfrs = match self.u32() {
+      Some(n) => n,
+      None => { return None; },
+    };
+    let mut clfrs = Vec::new();
+    for _ in 0..num_clfrs {
+      match self.strings(3) {
+        Some(clfr) => {
+          let mut clfr = clfr.into_iter();
+          clfrs.push(cedict::Classifier {
+            trad: clfr.next().unwrap(),
+            simp: clfr.next().unwrap(),
+            pinyin: clfr.next().unwrap(),
+          });
+        },
+        None => { return None; },
+      }
+    }
+    Some(cedict::Entry {
+      trad: trad,
+      simp: simp,
+      pinyin: pinyin,
+      tw_pinyin: tw_pinyin,
+      alt_pinyin: alt_pinyin,
+      defs: defs,
+      clfrs: clfrs,
+    })
+  }
+
+  fn u32s(&mut self, n: usize) -> Option<Vec<usize>> {
+    let mut rv = Vec::with_capacity(n);
+    for _ in 0..n {
+      match self.u32() {
+        Some(i) => rv.push(i as usize),
+        None => { return None; },
+      }
+    }
+    Some(rv)
+  }
+
+  fn tables(&mut self) -> Option<SearchTables> {
+    let headword_suffixes = match self.u32().and_then(|n| self.u32s(3 * n as usize)) {
+      Some(nums) => nums.chunks(3).map(|suffix| {
+        cedict::HeadwordSuffix { entry: suffix[0], trad: suffix[1] != 0, start: suffix[2] }
+      }).collect(),
+      None => { return None; },
+    };
+    let num_tokens = match self.u32() {
+      Some(n) => n,
+      None => { return None; },
+    };
+    let mut tokens = Vec::with_capacity(num_tokens as usize);
+    for _ in 0..num_tokens {
+      let token = match self.string() {
+        Some(token) => token,
+        None => { return None; },
+      };
+      match self.u32().and_then(|n| self.u32s(n as usize)) {
+        Some(idxs) => tokens.push((token, idxs)),
+        None => { return None; },
+      }
+    }
+    let token_suffixes = match self.u32().and_then(|n| self.u32s(2 * n as usize)) {
+      Some(nums) => nums.chunks(2).map(|suffix| (suffix[0], suffix[1])).collect(),
+      None => { return None; },
+    };
+    Some(SearchTables {
+      headword_suffixes: headword_suffixes,
+      english_idx: cedict::EnglishIndex { tokens: tokens, token_suffixes: token_suffixes },
+    })
+  }
+
+  fn error(&mut self, path: &str) -> Option<ParseError> {
+    let line = match self.u32() {
+      Some(0) => None,
+      Some(line) => Some(line as usize),
+      None => { return None; },
+    };
+    let tag = match self.u32() {
+      Some(tag) => tag,
+      None => { return None; },
+    };
+    let text = match self.string() {
+      Some(text) => text,
+      None => { return None; },
+    };
+    let kind = match tag {
+      0 => ErrorKind::MalformedEntry(text),
+      1 => ErrorKind::MalformedClassifier(text),
+      2 => ErrorKind::MalformedWordListLine(text),
+      3 => ErrorKind::NotInDict(text),
+      4 => ErrorKind::MalformedPreferredEntry(text),
+      5 => ErrorKind::UnusedPreferredEntry(text),
+      6 => ErrorKind::UnsatisfiablePreferredEntry(text),
+      7 => ErrorKind::ConflictingPreferredEntry(text),
+      8 => ErrorKind::ShadowingEntry(text),
+      9 => ErrorKind::MalformedSentence(text),
+      _ => { return None; },
+    };
+    Some(ParseError::new(path, line, kind))
+  }
+}
+
+fn decode(key: &[u8; 20], data: &[u8], path: &str, with_tables: bool)
+    -> Option<(Vec<cedict::Entry>, Vec<ParseError>, Option<SearchTables>)> {
+  // returns None if data isn't an index, or was built from a different dictionary. path is the
+  // dictionary's path, for the returned problems. The search tables are only decoded if
+  // with_tables is set.
+  let mut decoder = Decoder { data: data, pos: 0 };
+  if decoder.bytes(MAGIC.len()) != Some(MAGIC) || decoder.bytes(20) != Some(&key[..]) {
+    return None;
+  }
+  let num_entries = match decoder.u32() {
+    Some(n) => n,
+    None => { return None; },
+  };
+  let mut entries = Vec::with_capacity(num_entries as usize);
+  for _ in 0..num_entries {
+    match decoder.entry() {
+      Some(entry) => entries.push(entry),
+      None => { return None; },
+    }
+  }
+  let num_errors = match decoder.u32() {
+    Some(n) => n,
+    None => { return None; },
+  };
+  let mut errors = Vec::new();
+  for _ in 0..num_errors {
+    match decoder.error(path) {
+      Some(error) => errors.push(error),
+      None => { return None; },
+    }
+  }
+  if !with_tables {
+    return Some((entries, errors, None));
+  }
+  match decoder.tables() {
+    Some(tables) => Some((entries, errors, Some(tables))),
+    None => None,
+  }
+}
+
+fn load(cedict_path: &Path, index_path: &Path, with_tables: bool)
+    -> Result<(Vec<cedict::Entry>, Vec<ParseError>, Option<SearchTables>), String> {
+  // the guts of load_entries and load_dict. Failing to write the index isn't an error, since it's
+  // only a cache.
+  let path_str = cedict_path.to_string_lossy();
+  let key = source_key(cedict_path);
+  let mut data = Vec::new();
+  let cached = match (key, File::open(index_path).and_then(|mut f| f.read_to_end(&mut data))) {
+    (Some(ref key), Ok(_)) => decode(key, &data, &path_str, with_tables),
+    _ => None,
+  };
+  match cached {
+    Some(parsed) => { return Ok(parsed); },
+    None => (),
+  }
+
+  let text = try!(cedict::read_cedict(cedict_path));
+  let (entries, errors) = cedict::parse_entries_from(&text, &path_str);
+  let tables = SearchTables {
+    headword_suffixes: cedict::build_headword_suffixes(&entries),
+    english_idx: cedict::EnglishIndex::new(&entries),
+  };
+  match key {
+    Some(ref key) => {
+      let result : io::Result<()> = File::create(index_path)
+          .and_then(|mut f| f.write_all(&encode(key, &entries, &errors, &tables)));
+      match result {
+        Ok(_) => (),
+        Err(e) => {
+          println!("Warning: could not write dictionary index {}: {}", index_path.display(), e);
+        },
+      }
+    },
+    None => (),
+  }
+  Ok((entries, errors, if with_tables { Some(tables) } else { None }))
+}
+
+pub fn load_entries(cedict_path: &Path, index_path: &Path)
+    -> Result<(Vec<cedict::Entry>, Vec<ParseError>), String> {
+  // returns the entries of the CC-CEDICT file at cedict_path, and the problems found parsing it,
+  // using the index at index_path if it's up to date, and (re)building it otherwise
+  load(cedict_path, index_path, false).map(|(entries, errors, _)| (entries, errors))
+}
+
+pub fn load_dict(cedict_path: &Path, index_path: &Path) -> Result<cedict::Dict, String> {
+  // like load_entries, but returns a Dict of the entries that's ready for the searches that need
+  // extra tables (e.g. search_english)
+  let (entries, _, tables) = try!(load(cedict_path, index_path, true));
+  let tables = tables.unwrap();
+  Ok(cedict::Dict::with_search_tables(entries, tables.headword_suffixes, tables.english_idx))
+}
+
+#[cfg(test)]
+mod tests {
+  use cedict;
+  use parse_error::{ErrorKind, ParseError};
+  use std;
+  use std::fs::File;
+  use std::io::Write;
+
+  fn tables(entries: &Vec<cedict::Entry>) -> super::SearchTables {
+    super::SearchTables {
+      headword_suffixes: cedict::build_headword_suffixes(entries),
+      english_idx: cedict::EnglishIndex::new(entries),
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let (entries, errors) = cedict::parse_entries_from(
+        concat!(
+            "# comment\n",
+            "中國 中国 [Zhong1 guo2] /China/\n",
+            "個 个 [ge4] /individual/this/CL:個|个[ge4]/Taiwan pr. [ge3]/\n",
+            "落 落 [luo4] /to fall/also pr. [la4] and [lao4]/\n",
+            "not an entry\n"),
+        "cedict.txt");
+    assert_eq!(errors.len(), 1);
+    let key = [1; 20];
+    let data = super::encode(&key, &entries, &errors, &tables(&entries));
+    assert_eq!(super::decode(&key, &data, "cedict.txt", true),
+               Some((entries.clone(), errors.clone(), Some(tables(&entries)))));
+    assert_eq!(super::decode(&key, &data, "cedict.txt", false),
+               Some((entries.clone(), errors.clone(), None)));
+    // stale or truncated indexes are rejected
+    assert_eq!(super::decode(&[2; 20], &data, "cedict.txt", false), None);
+    assert_eq!(super::decode(&key, &data[..data.len() - 1], "cedict.txt", true), None);
+    // every kind of problem survives the round trip
+    let errors = vec!(
+        ErrorKind::UnusedPreferredEntry("a".to_string()),
+        ErrorKind::UnsatisfiablePreferredEntry("b".to_string()),
+        ErrorKind::ConflictingPreferredEntry("c".to_string()),
+        ErrorKind::ShadowingEntry("d".to_string()),
+        ErrorKind::MalformedSentence("e".to_string()))
+        .into_iter().map(|kind| ParseError::new("cedict.txt", Some(2), kind)).collect();
+    let data = super::encode(&key, &Vec::new(), &errors, &tables(&Vec::new()));
+    assert_eq!(super::decode(&key, &data, "cedict.txt", false), Some((Vec::new(), errors, None)));
+  }
+
+  #[test]
+  fn test_load() {
+    let cedict_path = std::env::temp_dir().join("hsk_flashcards_test_cedict.txt");
+    let index_path = std::env::temp_dir().join("hsk_flashcards_test_cedict.txt.index");
+    let _ = std::fs::remove_file(&index_path);
+    let write_cedict = |text: &str| {
+      File::create(&cedict_path).and_then(|mut f| f.write_all(text.as_bytes())).unwrap();
+    };
+    write_cedict("借 借 [jie4] /to lend/to borrow/\n");
+    let dict = super::load_dict(&cedict_path, &index_path).unwrap();
+    assert_eq!(dict.search_english("borrow").len(), 1);
+    // the second time around, the tables come from the index
+    let dict = super::load_dict(&cedict_path, &index_path).unwrap();
+    assert_eq!(dict.search_headwords("借", cedict::HeadwordSearch::Prefix).len(), 1);
+    assert_eq!(super::load_entries(&cedict_path, &index_path).unwrap().0.len(), 1);
+    // the index is rebuilt when the dictionary changes
+    write_cedict("借 借 [jie4] /to lend/to borrow/\n借用 借用 [jie4 yong4] /to borrow/\n");
+    assert_eq!(super::load_entries(&cedict_path, &index_path).unwrap().0.len(), 2);
+    std::fs::remove_file(&cedict_path).unwrap();
+    std::fs::remove_file(&index_path).unwrap();
+  }
+}
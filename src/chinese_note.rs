@@ -1,6 +1,114 @@
 use cedict;
+use std::collections::HashMap;
 
-pub struct ChineseNote<'a> {
-  pub ce: cedict::Entry<'a>,
+// How a note's entry was picked from the dictionary entries for its word.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rule {
+  // it was the only entry
+  Only,
+  // preferred_entries.yaml named it
+  Preferred,
+  // it was the last entry that isn't a reference to another word or a proper noun
+  Good,
+  // every entry was a reference or a proper noun, so the first was used
+  Fallback,
+  // several entries matched a Hanping export line, and the last was used
+  LastMatch,
+}
+
+impl Rule {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Rule::Only => "only",
+      Rule::Preferred => "preferred",
+      Rule::Good => "good",
+      Rule::Fallback => "fallback",
+      Rule::LastMatch => "last_match",
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct EntryChoice {
+  pub rule: Rule,
+  // all the entries for the word, including the chosen one
+  pub candidates: Vec<cedict::Entry>,
+}
+
+pub struct ChineseNote {
+  pub ce: cedict::Entry,
+  // other entries for the same word (other traditional forms or readings, like háng for 行) that are
+  // shown on the note as further senses; picked in preferred_entries.yaml, and usually empty
+  pub other_entries: Vec<cedict::Entry>,
   pub tags: Vec<String>,
+  // e.g. "Level 3" or "L1::Lesson 04"; used to place the note's cards in a sub-deck when requested
+  pub subdeck: Option<String>,
+  // for the build report
+  pub choice: EntryChoice,
+}
+
+impl ChineseNote {
+  pub fn entries(&self) -> Vec<&cedict::Entry> {
+    // ce, then other_entries
+    Some(&self.ce).into_iter().chain(self.other_entries.iter()).collect()
+  }
+}
+
+pub fn merge_duplicates(notes: Vec<ChineseNote>) -> (Vec<ChineseNote>, Vec<String>) {
+  // merges notes with the same entries (e.g. 等, which HSK lists as a verb and as a particle, but
+  // which has one entry in CC-CEDICT) into the first of them, which gets all their tags; also
+  // returns the words of the notes that were merged away
+  let mut rv : Vec<ChineseNote> = Vec::new();
+  let mut removed = Vec::new();
+  let mut idxs = HashMap::<String, usize>::new();
+  for note in notes {
+    let key = note.entries().into_iter()
+        .map(cedict::format_entry)
+        .collect::<Vec<_>>().connect("\n");
+    match idxs.get(&key) {
+      Some(&i) => {
+        removed.push(note.ce.simp.clone());
+        for tag in note.tags {
+          if !rv[i].tags.contains(&tag) {
+            rv[i].tags.push(tag);
+          }
+        }
+        continue;
+      },
+      None => (),
+    }
+    idxs.insert(key, rv.len());
+    rv.push(note);
+  }
+  (rv, removed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cedict;
+
+  #[test]
+  fn test_merge_duplicates() {
+    let entries = cedict::parse_entries(concat!(
+        "等 等 [deng3] /to wait for/class/\n",
+        "行 行 [xing2] /to walk/\n",
+        "行 行 [hang2] /row/\n"));
+    let note = |ce: &cedict::Entry, others: &[cedict::Entry], tag: &str| ChineseNote {
+      ce: ce.clone(),
+      other_entries: others.to_vec(),
+      tags: vec!(tag.to_string()),
+      subdeck: None,
+      choice: EntryChoice { rule: Rule::Only, candidates: vec!(ce.clone()) },
+    };
+    let (notes, removed) = merge_duplicates(vec!(
+        note(&entries[0], &[], "HSK_Level_2"),
+        note(&entries[1], &[], "HSK_Level_1"),
+        note(&entries[0], &[], "HSK_Level_4"),
+        note(&entries[1], &entries[2..], "HSK_Level_1")));
+    assert_eq!(notes.len(), 3);
+    assert_eq!(notes[0].tags, vec!("HSK_Level_2", "HSK_Level_4"));
+    assert_eq!(notes[2].entries(), vec!(&entries[1], &entries[2]));
+    assert_eq!(removed, vec!("等"));
+  }
 }
@@ -0,0 +1,294 @@
+extern crate rustc_serialize;
+extern crate yaml;
+
+use rustc_serialize::json;
+use rustc_serialize::json::ToJson;
+use std::collections::BTreeMap;
+use yaml::constructor::*;
+
+// Typed versions of the note type ("model"), deck and collection settings that Anki stores as JSON
+// in the col row of a legacy collection. AnkiPackage serializes these with to_json() for schema 11,
+// and anki21.rs converts them to protobuf for schema 18.
+
+#[derive(Clone, RustcDecodable, RustcEncodable)]
+pub struct Field {
+  pub name: String,
+  pub ord: u32,
+  pub font: String,
+  pub size: u32,
+  pub rtl: bool,
+  pub sticky: bool,
+  pub media: Vec<String>,
+}
+
+impl Field {
+  pub fn new(name: &str, ord: u32, size: u32) -> Field {
+    Field {
+      name: name.to_string(),
+      ord: ord,
+      font: "Liberation Sans".to_string(),
+      size: size,
+      rtl: false,
+      sticky: false,
+      media: Vec::new(),
+    }
+  }
+}
+
+pub fn fields_from_json(flds_json: &str) -> Vec<Field> {
+  json::decode(flds_json).unwrap()
+}
+
+#[derive(Clone, RustcEncodable)]
+pub struct Template {
+  pub name: String,
+  pub ord: u32,
+  pub qfmt: String,
+  pub afmt: String,
+  pub bqfmt: String,
+  pub bafmt: String,
+  // deck override for cards generated from this template
+  pub did: Option<i64>,
+}
+
+fn yaml_string(y: YamlStandardData) -> String {
+  match y {
+    YamlStandardData::YamlString(s) => s,
+    _ => panic!("data wasn't a string"),
+  }
+}
+
+pub fn templates_from_yaml(tmpls_yaml: &str) -> Vec<Template> {
+  // tmpls_yaml is a sequence of mappings with name, qfmt and afmt keys
+  let mut tmpls = Vec::new();
+  let yaml_doc = yaml::parse_bytes_utf8(tmpls_yaml.as_bytes())
+      .unwrap()
+      .pop();
+  let seq = match yaml_doc {
+    Some(YamlStandardData::YamlSequence(s)) => s,
+    _ => panic!("data wasn't a sequence"),
+  };
+  for item in seq {
+    let map = match item {
+      YamlStandardData::YamlMapping(m) => m,
+      _ => panic!("data wasn't a mapping"),
+    };
+    let mut tmpl = Template {
+      name: String::new(),
+      ord: tmpls.len() as u32,
+      qfmt: String::new(),
+      afmt: String::new(),
+      bqfmt: String::new(),
+      bafmt: String::new(),
+      did: None,
+    };
+    for (key, val) in map {
+      let key = yaml_string(key);
+      let val = yaml_string(val);
+      match &key as &str {
+        "name" => { tmpl.name = val; },
+        "qfmt" => { tmpl.qfmt = val; },
+        "afmt" => { tmpl.afmt = val; },
+        "bqfmt" => { tmpl.bqfmt = val; },
+        "bafmt" => { tmpl.bafmt = val; },
+        _ => panic!("unknown template key {}", key),
+      }
+    }
+    tmpls.push(tmpl);
+  }
+  tmpls
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ModelKind {
+  Standard,
+  Cloze,
+}
+
+// Which fields must be non-empty for the card generated by template ord to exist. Anki recomputes
+// these itself, but older versions expect them to be present.
+#[derive(Clone)]
+pub struct CardRequirement {
+  pub ord: u32,
+  // "all", "any" or "none"
+  pub kind: String,
+  pub fields: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct Model {
+  pub id: i64,
+  pub name: String,
+  pub kind: ModelKind,
+  pub flds: Vec<Field>,
+  pub tmpls: Vec<Template>,
+  pub css: String,
+  // index of the field that notes are sorted by in the browser
+  pub sortf: u32,
+  // deck that new cards go to by default
+  pub did: i64,
+  pub req: Vec<CardRequirement>,
+  pub latex_pre: String,
+  pub latex_post: String,
+}
+
+impl Model {
+  pub fn new(id: i64, name: &str, did: i64, flds: Vec<Field>, tmpls: Vec<Template>, css: &str)
+      -> Model {
+    Model {
+      id: id,
+      name: name.to_string(),
+      kind: ModelKind::Standard,
+      flds: flds,
+      tmpls: tmpls,
+      css: css.to_string(),
+      sortf: 0,
+      did: did,
+      req: Vec::new(),
+      latex_pre: concat!(
+          "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n",
+          "\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n",
+          "\\begin{document}\n").to_string(),
+      latex_post: "\\end{document}".to_string(),
+    }
+  }
+
+  pub fn to_json(&self, mtime: i64) -> json::Json {
+    let mut rv = BTreeMap::new();
+    rv.insert("css".to_string(), self.css.to_json());
+    rv.insert("did".to_string(), self.did.to_json());
+    rv.insert("flds".to_string(),
+              json::Json::from_str(&json::encode(&self.flds).unwrap()).unwrap());
+    // legacy collections store model IDs as strings here, even though they're numbers elsewhere
+    rv.insert("id".to_string(), self.id.to_string().to_json());
+    rv.insert("latexPost".to_string(), self.latex_post.to_json());
+    rv.insert("latexPre".to_string(), self.latex_pre.to_json());
+    rv.insert("mod".to_string(), mtime.to_json());
+    rv.insert("name".to_string(), self.name.to_json());
+    rv.insert("req".to_string(), json::Json::Array(self.req.iter().map(|r| {
+      json::Json::Array(vec!(r.ord.to_json(), r.kind.to_json(), r.fields.to_json()))
+    }).collect()));
+    rv.insert("sortf".to_string(), self.sortf.to_json());
+    rv.insert("tags".to_string(), json::Json::Array(Vec::new()));
+    rv.insert("tmpls".to_string(),
+              json::Json::from_str(&json::encode(&self.tmpls).unwrap()).unwrap());
+    rv.insert("type".to_string(), match self.kind {
+      ModelKind::Standard => 0i64,
+      ModelKind::Cloze => 1i64,
+    }.to_json());
+    rv.insert("usn".to_string(), (-1i64).to_json());
+    rv.insert("vers".to_string(), json::Json::Array(Vec::new()));
+    json::Json::Object(rv)
+  }
+}
+
+#[derive(Clone)]
+pub struct Deck {
+  pub id: i64,
+  // components are separated by ::, e.g. HSK::Level 3
+  pub name: String,
+  pub desc: String,
+  // ID of the deck options group
+  pub conf: i64,
+}
+
+impl Deck {
+  pub fn new(id: i64, name: &str) -> Deck {
+    Deck {
+      id: id,
+      name: name.to_string(),
+      desc: String::new(),
+      conf: 1,
+    }
+  }
+
+  pub fn to_json(&self, mtime: i64) -> json::Json {
+    let today = json::Json::Array(vec!(0i64.to_json(), 0i64.to_json()));
+    let mut rv = BTreeMap::new();
+    rv.insert("collapsed".to_string(), false.to_json());
+    rv.insert("conf".to_string(), self.conf.to_json());
+    rv.insert("desc".to_string(), self.desc.to_json());
+    rv.insert("dyn".to_string(), 0i64.to_json());
+    rv.insert("extendNew".to_string(), 10i64.to_json());
+    rv.insert("extendRev".to_string(), 50i64.to_json());
+    rv.insert("id".to_string(), self.id.to_json());
+    rv.insert("lrnToday".to_string(), today.clone());
+    rv.insert("mod".to_string(), mtime.to_json());
+    rv.insert("name".to_string(), self.name.to_json());
+    rv.insert("newToday".to_string(), today.clone());
+    rv.insert("revToday".to_string(), today.clone());
+    rv.insert("timeToday".to_string(), today);
+    rv.insert("usn".to_string(), (-1i64).to_json());
+    json::Json::Object(rv)
+  }
+}
+
+// Collection-wide settings (col.conf in schema 11, the config table in schema 18).
+#[derive(Clone)]
+pub struct CollectionConfig {
+  pub cur_deck: i64,
+  pub cur_model: i64,
+  pub active_decks: Vec<i64>,
+  pub next_pos: i64,
+  pub sort_type: String,
+}
+
+impl CollectionConfig {
+  pub fn new(cur_deck: i64, cur_model: i64) -> CollectionConfig {
+    CollectionConfig {
+      cur_deck: cur_deck,
+      cur_model: cur_model,
+      active_decks: vec!(cur_deck),
+      next_pos: 1,
+      sort_type: "noteFld".to_string(),
+    }
+  }
+
+  pub fn items(&self) -> Vec<(&'static str, json::Json)> {
+    vec!(
+        ("activeDecks", self.active_decks.to_json()),
+        ("addToCur", true.to_json()),
+        ("collapseTime", 1200i64.to_json()),
+        ("curDeck", self.cur_deck.to_json()),
+        ("curModel", self.cur_model.to_string().to_json()),
+        ("dueCounts", true.to_json()),
+        ("estTimes", true.to_json()),
+        ("newBury", true.to_json()),
+        ("newSpread", 0i64.to_json()),
+        ("nextPos", self.next_pos.to_json()),
+        ("sortBackwards", false.to_json()),
+        ("sortType", self.sort_type.to_json()),
+        ("timeLim", 0i64.to_json()),
+    )
+  }
+
+  pub fn to_json(&self) -> json::Json {
+    let mut rv = BTreeMap::new();
+    for (key, val) in self.items() {
+      rv.insert(key.to_string(), val);
+    }
+    json::Json::Object(rv)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_deck_name_is_not_templated() {
+    // names that look like the placeholders the old string templating used, or that contain
+    // quotes, must come through unchanged
+    let deck = Deck::new(2, "TMPLS's \"FLDS\" deck");
+    assert_eq!(deck.to_json(0).find("name").unwrap().as_string(), Some("TMPLS's \"FLDS\" deck"));
+  }
+
+  #[test]
+  fn test_templates_from_yaml() {
+    let tmpls = templates_from_yaml(
+        "- name: A\n  qfmt: q\n  afmt: a\n- name: B\n  qfmt: q\n  afmt: a\n");
+    assert_eq!(tmpls.len(), 2);
+    assert_eq!(tmpls[1].name, "B");
+    assert_eq!(tmpls[1].ord, 1);
+  }
+}
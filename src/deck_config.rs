@@ -0,0 +1,293 @@
+extern crate rustc_serialize;
+extern crate yaml;
+
+use protobuf;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use yaml::constructor::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NewOrder {
+  Due,
+  Random,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LeechAction {
+  Suspend,
+  Tag,
+}
+
+// The deck options ("options group" in Anki's UI) that the generated decks use. Field names match
+// the keys accepted in --deck_config files and by --deck_option. Steps are in minutes, intervals
+// in days.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DeckConfig {
+  pub new_per_day: u32,
+  pub learning_steps: Vec<f64>,
+  pub graduating_interval: u32,
+  pub easy_interval: u32,
+  pub initial_ease: f64,
+  pub new_order: NewOrder,
+  pub bury_new: bool,
+  pub reviews_per_day: u32,
+  pub easy_bonus: f64,
+  pub interval_modifier: f64,
+  pub maximum_interval: u32,
+  pub bury_reviews: bool,
+  pub relearning_steps: Vec<f64>,
+  pub lapse_new_interval: f64,
+  pub minimum_interval: u32,
+  pub leech_threshold: u32,
+  pub leech_action: LeechAction,
+  pub autoplay: bool,
+}
+
+fn parse_num<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+  value.trim().parse().map_err(|_| format!("Invalid value for {}: {}", key, value))
+}
+
+fn parse_steps(key: &str, value: &str) -> Result<Vec<f64>, String> {
+  // steps are separated by spaces and/or commas, e.g. "1 10" or "1, 10"
+  let mut rv = Vec::new();
+  for step in value.split(|c| c == ' ' || c == ',').filter(|s| *s != "") {
+    rv.push(try!(parse_num(key, step)));
+  }
+  Ok(rv)
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+  match value.trim() {
+    "true" | "yes" | "1" => Ok(true),
+    "false" | "no" | "0" => Ok(false),
+    _ => Err(format!("Invalid value for {}: {}", key, value)),
+  }
+}
+
+fn yaml_scalar_string(key: &str, y: YamlStandardData) -> Result<String, String> {
+  match y {
+    YamlStandardData::YamlString(s) => Ok(s),
+    YamlStandardData::YamlInteger(i) => Ok(i.to_string()),
+    YamlStandardData::YamlFloat(f) => Ok(f.to_string()),
+    YamlStandardData::YamlBool(b) => Ok(b.to_string()),
+    YamlStandardData::YamlSequence(seq) => {
+      let mut items = Vec::new();
+      for item in seq {
+        items.push(try!(yaml_scalar_string(key, item)));
+      }
+      Ok(items.connect(" "))
+    },
+    _ => Err(format!("Invalid value for {}", key)),
+  }
+}
+
+fn json_steps(steps: &Vec<f64>) -> json::Json {
+  json::Json::Array(steps.iter().map(|&s| json::Json::F64(s)).collect())
+}
+
+impl DeckConfig {
+  pub fn new() -> DeckConfig {
+    // Anki's own defaults
+    DeckConfig {
+      new_per_day: 20,
+      learning_steps: vec!(1.0, 10.0),
+      graduating_interval: 1,
+      easy_interval: 4,
+      initial_ease: 2.5,
+      new_order: NewOrder::Due,
+      bury_new: true,
+      reviews_per_day: 100,
+      easy_bonus: 1.3,
+      interval_modifier: 1.0,
+      maximum_interval: 36500,
+      bury_reviews: true,
+      relearning_steps: vec!(10.0),
+      lapse_new_interval: 0.0,
+      minimum_interval: 1,
+      leech_threshold: 8,
+      leech_action: LeechAction::Suspend,
+      autoplay: true,
+    }
+  }
+
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    match key {
+      "new_per_day" => { self.new_per_day = try!(parse_num(key, value)); },
+      "learning_steps" => { self.learning_steps = try!(parse_steps(key, value)); },
+      "graduating_interval" => { self.graduating_interval = try!(parse_num(key, value)); },
+      "easy_interval" => { self.easy_interval = try!(parse_num(key, value)); },
+      "initial_ease" => { self.initial_ease = try!(parse_num(key, value)); },
+      "new_order" => {
+        self.new_order = match value.trim() {
+          "due" => NewOrder::Due,
+          "random" => NewOrder::Random,
+          _ => { return Err(format!("Invalid value for {}: {}", key, value)); },
+        };
+      },
+      "bury_new" => { self.bury_new = try!(parse_bool(key, value)); },
+      "reviews_per_day" => { self.reviews_per_day = try!(parse_num(key, value)); },
+      "easy_bonus" => { self.easy_bonus = try!(parse_num(key, value)); },
+      "interval_modifier" => { self.interval_modifier = try!(parse_num(key, value)); },
+      "maximum_interval" => { self.maximum_interval = try!(parse_num(key, value)); },
+      "bury_reviews" => { self.bury_reviews = try!(parse_bool(key, value)); },
+      "relearning_steps" => { self.relearning_steps = try!(parse_steps(key, value)); },
+      "lapse_new_interval" => { self.lapse_new_interval = try!(parse_num(key, value)); },
+      "minimum_interval" => { self.minimum_interval = try!(parse_num(key, value)); },
+      "leech_threshold" => { self.leech_threshold = try!(parse_num(key, value)); },
+      "leech_action" => {
+        self.leech_action = match value.trim() {
+          "suspend" => LeechAction::Suspend,
+          "tag" => LeechAction::Tag,
+          _ => { return Err(format!("Invalid value for {}: {}", key, value)); },
+        };
+      },
+      "autoplay" => { self.autoplay = try!(parse_bool(key, value)); },
+      _ => { return Err(format!("Unknown deck option {}", key)); },
+    }
+    Ok(())
+  }
+
+  pub fn set_from_yaml(&mut self, yaml_str: &str) -> Result<(), String> {
+    // applies the options in a YAML mapping such as
+    //   new_per_day: 10
+    //   learning_steps: [1, 10, 60]
+    let mut docs = try!(yaml::parse_bytes_utf8(yaml_str.as_bytes())
+        .map_err(|e| format!("Could not parse deck config: {:?}", e)));
+    let mapping = match docs.pop() {
+      Some(YamlStandardData::YamlMapping(m)) => m,
+      None => { return Ok(()); },
+      _ => { return Err("Deck config wasn't a mapping".to_string()); },
+    };
+    for (key, val) in mapping {
+      let key = match key {
+        YamlStandardData::YamlString(s) => s,
+        _ => { return Err("Deck config key wasn't a string".to_string()); },
+      };
+      let val = try!(yaml_scalar_string(&key, val));
+      try!(self.set(&key, &val));
+    }
+    Ok(())
+  }
+
+  pub fn to_json(&self, id: i64) -> json::Json {
+    // the legacy (schema 11) representation stored in col.dconf
+    let mut new = BTreeMap::new();
+    new.insert("bury".to_string(), json::Json::Boolean(self.bury_new));
+    new.insert("delays".to_string(), json_steps(&self.learning_steps));
+    new.insert("initialFactor".to_string(), json::Json::I64((self.initial_ease * 1000.0).round() as i64));
+    new.insert("ints".to_string(), json::Json::Array(vec!(
+        json::Json::U64(self.graduating_interval as u64),
+        json::Json::U64(self.easy_interval as u64),
+        json::Json::U64(7))));
+    new.insert("order".to_string(), json::Json::U64(match self.new_order {
+      NewOrder::Random => 0,
+      NewOrder::Due => 1,
+    }));
+    new.insert("perDay".to_string(), json::Json::U64(self.new_per_day as u64));
+    new.insert("separate".to_string(), json::Json::Boolean(true));
+
+    let mut rev = BTreeMap::new();
+    rev.insert("bury".to_string(), json::Json::Boolean(self.bury_reviews));
+    rev.insert("ease4".to_string(), json::Json::F64(self.easy_bonus));
+    rev.insert("fuzz".to_string(), json::Json::F64(0.05));
+    rev.insert("ivlFct".to_string(), json::Json::F64(self.interval_modifier));
+    rev.insert("maxIvl".to_string(), json::Json::U64(self.maximum_interval as u64));
+    rev.insert("minSpace".to_string(), json::Json::U64(1));
+    rev.insert("perDay".to_string(), json::Json::U64(self.reviews_per_day as u64));
+
+    let mut lapse = BTreeMap::new();
+    lapse.insert("delays".to_string(), json_steps(&self.relearning_steps));
+    lapse.insert("leechAction".to_string(), json::Json::U64(match self.leech_action {
+      LeechAction::Suspend => 0,
+      LeechAction::Tag => 1,
+    }));
+    lapse.insert("leechFails".to_string(), json::Json::U64(self.leech_threshold as u64));
+    lapse.insert("minInt".to_string(), json::Json::U64(self.minimum_interval as u64));
+    lapse.insert("mult".to_string(), json::Json::F64(self.lapse_new_interval));
+
+    let mut rv = BTreeMap::new();
+    rv.insert("autoplay".to_string(), json::Json::Boolean(self.autoplay));
+    rv.insert("id".to_string(), json::Json::I64(id));
+    rv.insert("lapse".to_string(), json::Json::Object(lapse));
+    rv.insert("maxTaken".to_string(), json::Json::U64(60));
+    rv.insert("mod".to_string(), json::Json::U64(0));
+    rv.insert("name".to_string(), json::Json::String("Default".to_string()));
+    rv.insert("new".to_string(), json::Json::Object(new));
+    rv.insert("replayq".to_string(), json::Json::Boolean(true));
+    rv.insert("rev".to_string(), json::Json::Object(rev));
+    rv.insert("timer".to_string(), json::Json::U64(0));
+    rv.insert("usn".to_string(), json::Json::U64(0));
+    json::Json::Object(rv)
+  }
+
+  pub fn to_protobuf(&self) -> protobuf::Message {
+    // the schema 18 representation stored in deck_config.config
+    let learning_steps : Vec<f32> = self.learning_steps.iter().map(|&s| s as f32).collect();
+    let relearning_steps : Vec<f32> = self.relearning_steps.iter().map(|&s| s as f32).collect();
+    let mut rv = protobuf::Message::new();
+    rv
+        .floats(1, &learning_steps)  // learn_steps
+        .floats(2, &relearning_steps)  // relearn_steps
+        .uint(9, self.new_per_day as u64)  // new_per_day
+        .uint(10, self.reviews_per_day as u64)  // reviews_per_day
+        .float(11, self.initial_ease as f32)  // initial_ease
+        .float(12, self.easy_bonus as f32)  // easy_multiplier
+        .float(13, 1.2)  // hard_multiplier
+        .float(14, self.lapse_new_interval as f32)  // lapse_multiplier
+        .float(15, self.interval_modifier as f32)  // interval_multiplier
+        .uint(16, self.maximum_interval as u64)  // maximum_review_interval
+        .uint(17, self.minimum_interval as u64)  // minimum_lapse_interval
+        .uint(18, self.graduating_interval as u64)  // graduating_interval_good
+        .uint(19, self.easy_interval as u64)  // graduating_interval_easy
+        .uint(20, match self.new_order {  // new_card_insert_order
+          NewOrder::Due => 0,
+          NewOrder::Random => 1,
+        })
+        .uint(21, match self.leech_action {  // leech_action
+          LeechAction::Suspend => 0,
+          LeechAction::Tag => 1,
+        })
+        .uint(22, self.leech_threshold as u64)  // leech_threshold
+        .bool(23, !self.autoplay)  // disable_autoplay
+        .uint(24, 60)  // cap_answer_time_to_secs
+        .bool(27, self.bury_new)  // bury_new
+        .bool(28, self.bury_reviews);  // bury_reviews
+    rv
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_set_from_yaml() {
+    let mut config = DeckConfig::new();
+    config.set_from_yaml("new_per_day: 5\nlearning_steps: [1, 10, 60]\nnew_order: random\n")
+        .unwrap();
+    assert_eq!(config.new_per_day, 5);
+    assert_eq!(config.learning_steps, vec!(1.0, 10.0, 60.0));
+    assert_eq!(config.new_order, NewOrder::Random);
+    assert_eq!(config.reviews_per_day, 100);
+  }
+
+  #[test]
+  fn test_set_rejects_bad_values() {
+    let mut config = DeckConfig::new();
+    assert!(config.set("new_per_day", "lots").is_err());
+    assert!(config.set("no_such_option", "1").is_err());
+    assert!(config.set("learning_steps", "1, 10").is_ok());
+    assert_eq!(config.learning_steps, vec!(1.0, 10.0));
+  }
+
+  #[test]
+  fn test_encodings() {
+    let mut config = DeckConfig::new();
+    config.set("initial_ease", "2.3").unwrap();
+    assert_eq!(config.to_json(1).find_path(&["new", "initialFactor"]).and_then(|f| f.as_i64()),
+               Some(2300));
+    // cap_answer_time_to_secs, bury_new and bury_reviews
+    assert!(config.to_protobuf().bytes()
+        .ends_with(&[0xc0, 0x01, 0x3c, 0xd8, 0x01, 0x01, 0xe0, 0x01, 0x01]));
+  }
+}
@@ -1,33 +1,62 @@
 use cedict;
 use chinese_note;
+use parse_error::{ErrorKind, ParseError};
+use preferred_entry;
 use std;
 
-pub fn get_chinese_notes<'a>(wordlist: &'a str, extra_entries: &'a str)
-    -> Vec<chinese_note::ChineseNote<'a>> {
-  let dict = cedict::Dict::new_with_extra_entries(extra_entries);
+pub fn get_chinese_notes(wordlist: &str, wordlist_path: &str, dict: &cedict::Dict,
+                         preferred_overrides: &Vec<(String, String)>)
+    -> (Vec<chinese_note::ChineseNote>, Vec<ParseError>) {
+  // wordlist is the contents of the Hanping export at wordlist_path; dict is CC-CEDICT plus the
+  // entries from --extra_entries, and preferred_overrides are --preferred_entries files (see
+  // PreferredEntryGetter::new)
+  let (preferred, mut errors) =
+      preferred_entry::PreferredEntryGetter::new(dict, preferred_overrides);
   let mut rv = Vec::new();
-  for line in wordlist.split("\n") {
+  for (i, line) in wordlist.split("\n").enumerate() {
+    if line.trim() == "" {
+      continue;
+    }
     let pl = match parse_line(&line) {
       Ok(pl) => pl,
-      Err(s) => {
-        panic!("{}", s);
+      Err(_) => {
+        errors.push(ParseError::new(
+            wordlist_path, Some(i + 1), ErrorKind::MalformedWordListLine(line.to_string())));
+        continue;
       }
     };
     let entries = dict.search(
         cedict::DictSearchParams{
             trad: Some(&pl.trad), simp: Some(&pl.simp), pinyin: Some(&pl.pinyin)});
-    if entries.len() != 1 {
-      println!("Warning: number of entries for {:?} was {}; not exactly 1.", pl, entries.len());
+    if entries.len() == 0 {
+      errors.push(ParseError::new(
+          wordlist_path, Some(i + 1), ErrorKind::NotInDict(pl.trad.clone())));
     }
     if entries.len() > 0 {
       // entries[entries.len() - 1] causes it to prefer entries with lowercase pinyin, e.g.
       //   乾 干 [gan1] /dry/clean/in vain/dried food/foster/adoptive/to ignore/
       // will be preferred over
       //   乾 干 [Gan1] /surname Gan/
-      rv.push(chinese_note::ChineseNote{ce: entries[entries.len() - 1].clone(), tags: vec!()});
+      // unless the preferred entries pick others
+      let (mut picked, rule) = match preferred.pick(&pl.simp, &entries) {
+        Some(picked) => (picked, chinese_note::Rule::Preferred),
+        None if entries.len() == 1 => (entries.clone(), chinese_note::Rule::Only),
+        None => (vec!(entries[entries.len() - 1].clone()), chinese_note::Rule::LastMatch),
+      };
+      let ce = picked.remove(0);
+      rv.push(chinese_note::ChineseNote{
+          ce: ce,
+          other_entries: picked,
+          tags: vec!(),
+          subdeck: None,
+          choice: chinese_note::EntryChoice{
+              rule: rule,
+              candidates: entries.clone(),
+          },
+      });
     }
   }
-  rv // TODO
+  (rv, errors)
 }
 
 pub struct ParsedLine {
@@ -88,6 +117,9 @@ pub fn parse_line(line: &str) -> Result<ParsedLine, String> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use build_report;
+  use cedict;
+  use chinese_note;
 
   #[test]
   fn jilupian_line_parses_correctly() {
@@ -107,4 +139,22 @@ mod tests {
     assert_eq!(parsed_line.simp, "粗");
     assert_eq!(parsed_line.pinyin, "cu1");
   }
+
+  #[test]
+  fn test_last_match_is_reported() {
+    // a word with several entries and no preferred entry gets the last one, and --report lists
+    // all of them
+    let dict = cedict::Dict::new(concat!(
+        "甲 甲 [Jia3] /surname Jia/\n",
+        "甲 甲 [jia3] /first of the ten heavenly stems/\n"));
+    let (notes, _) = get_chinese_notes("甲 jiǎ first\n", "hanping.txt", &dict, &Vec::new());
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].ce.pinyin, "jia3");
+    assert_eq!(notes[0].choice.rule, chinese_note::Rule::LastMatch);
+    let report = build_report::BuildReport::new(&Vec::new(), &notes);
+    assert_eq!(report.to_csv(), concat!(
+        "section,file,line,word,rule,entry,detail\n",
+        "lookup,,,甲,last_match,甲 甲 [Jia3] /surname Jia/,\n",
+        "lookup,,,甲,last_match,甲 甲 [jia3] /first of the ten heavenly stems/,chosen\n"));
+  }
 }
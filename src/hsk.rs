@@ -2,32 +2,95 @@
 
 use chinese_note;
 use cedict;
+use parse_error::{ErrorKind, ParseError};
 use preferred_entry;
+use std::collections::HashMap;
+
+const WORDLIST_PATH : &'static str = "src/hsk_wordlist.csv";
+pub const EXTRA_DICT_PATH : &'static str = "src/extra_dict.txt";
+pub const EXTRA_DICT : &'static str = include_str!("extra_dict.txt");
 
 #[derive(Clone)]
 struct HskWord {
   simp: String,
   part_of_speech: String,  // usually ""
   level: u32,
+  // in hsk_wordlist.csv, 1-based
+  line: usize,
 }
 
-fn get_hsk_words() -> Vec<HskWord> {
+fn get_hsk_words() -> (Vec<HskWord>, Vec<ParseError>) {
   let wordlist = include_str!("hsk_wordlist.csv");
-  let re = regex!(r"(.+?),(.*?),(\d)\n");
+  let re = regex!(r"^(.+?),(.*?),(\d)$");
   let mut rv = Vec::new();
-  for cap in re.captures_iter(wordlist) {
-    rv.push(
-        HskWord{simp: cap.at(1).unwrap_or("").to_string(),
-                part_of_speech: cap.at(2).unwrap_or("").to_string(),
-                level: cap.at(3).unwrap_or("0").parse().unwrap_or(0)});
+  let mut errors = Vec::new();
+  for (i, line) in wordlist.lines().enumerate() {
+    if line.trim() == "" {
+      continue;
+    }
+    match re.captures(line) {
+      Some(cap) => {
+        rv.push(
+            HskWord{simp: cap.at(1).unwrap_or("").to_string(),
+                    part_of_speech: cap.at(2).unwrap_or("").to_string(),
+                    level: cap.at(3).unwrap_or("0").parse().unwrap_or(0),
+                    line: i + 1});
+      },
+      None => {
+        errors.push(ParseError::new(
+            WORDLIST_PATH, Some(i + 1), ErrorKind::MalformedWordListLine(line.to_string())));
+      },
+    }
+  }
+  (rv, errors)
+}
+
+pub fn get_levels() -> HashMap<String, u32> {
+  // the HSK level of each (simplified) word on the list; words listed at several levels get the
+  // lowest one
+  let mut rv = HashMap::new();
+  for word in get_hsk_words().0 {
+    let level = rv.entry(word.simp).or_insert(word.level);
+    if word.level < *level {
+      *level = word.level;
+    }
   }
   rv
 }
 
-pub fn get_chinese_notes() -> Vec<chinese_note::ChineseNote<'static>> {
-  let dict = cedict::Dict::new_with_extra_entries(include_str!("extra_dict.txt"));
-  let preferred = preferred_entry::PreferredEntryGetter::new(&dict);
-  let hsk_words = get_hsk_words();
+pub fn preferred_entry_keys() -> Vec<String> {
+  // the keys that the words on the list are looked up by in preferred_entries.yaml: the word,
+  // followed by its part of speech if the list gives one
+  get_hsk_words().0.into_iter()
+      .map(|word| {
+        if word.part_of_speech == "" { word.simp } else { word.simp + " " + &word.part_of_speech }
+      })
+      .collect()
+}
+
+pub fn sort_by_level(entries: &mut Vec<cedict::Entry>, levels: &HashMap<String, u32>) {
+  // puts the entries for HSK words first, lowest level first, keeping the order of entries at the
+  // same level (and of the entries for words that aren't on the list, which go last)
+  entries.sort_by(|a, b| {
+    let level = |e: &cedict::Entry| *levels.get(&e.simp).unwrap_or(&u32::max_value());
+    level(a).cmp(&level(b))
+  });
+}
+
+pub fn get_dict(cedict: &Vec<cedict::Entry>) -> (cedict::Dict, Vec<ParseError>) {
+  // CC-CEDICT plus the entries in extra_dict.txt, and the problems found parsing the latter
+  let (extra_entries, errors) = cedict::parse_entries_from(EXTRA_DICT, EXTRA_DICT_PATH);
+  (cedict::Dict::new_with_extra_entries(cedict, extra_entries), errors)
+}
+
+pub fn get_chinese_notes(dict: &cedict::Dict, preferred_overrides: &Vec<(String, String)>)
+    -> (Vec<chinese_note::ChineseNote>, Vec<ParseError>) {
+  // dict is from get_dict; preferred_overrides are --preferred_entries files (see
+  // PreferredEntryGetter::new)
+  let (preferred, mut errors) =
+      preferred_entry::PreferredEntryGetter::new(dict, preferred_overrides);
+  let (hsk_words, wordlist_errors) = get_hsk_words();
+  errors.extend(wordlist_errors.into_iter());
 
   let mut rv = Vec::new();
 
@@ -38,14 +101,22 @@ pub fn get_chinese_notes() -> Vec<chinese_note::ChineseNote<'static>> {
       continue;
     }
     if dict.search_simp(&word.simp).len() == 0 {
-      println!("{} not in dict", word.simp);
+      errors.push(ParseError::new(
+          WORDLIST_PATH, Some(word.line), ErrorKind::NotInDict(word.simp.clone())));
       continue;
     }
-    let ce = preferred.get(
+    let (ce, other_entries, choice, problem) = preferred.choose(
         &word.simp,
         if word.part_of_speech == "" { None } else { Some(&word.part_of_speech) });
+    match problem {
+      Some(kind) => { errors.push(ParseError::new(WORDLIST_PATH, Some(word.line), kind)); },
+      None => (),
+    }
     rv.push(chinese_note::ChineseNote{ce: ce,
-                                      tags: vec!(format!("HSK_Level_{}", word.level))});
+                                      other_entries: other_entries,
+                                      tags: vec!(format!("HSK_Level_{}", word.level)),
+                                      subdeck: Some(format!("Level {}", word.level)),
+                                      choice: choice});
   }
-  rv
+  (rv, errors)
 }
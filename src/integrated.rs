@@ -2,25 +2,80 @@ extern crate csv;
 
 use chinese_note;
 use cedict;
+use parse_error::{ErrorKind, ParseError};
 use preferred_entry;
 
-pub fn get_chinese_notes() -> Vec<chinese_note::ChineseNote<'static>> {
-  let dict = cedict::Dict::new_with_extra_entries(include_str!("extra_dict_integrated.txt"));
-  let preferred = preferred_entry::PreferredEntryGetter::new(&dict);
+const WORDLIST_PATH : &'static str = "src/integrated_wordlist.csv";
+pub const EXTRA_DICT_PATH : &'static str = "src/extra_dict_integrated.txt";
+pub const EXTRA_DICT : &'static str = include_str!("extra_dict_integrated.txt");
+
+struct IntegratedWord {
+  simp: String,
+  level: u32,
+  lesson: u32,
+  // in integrated_wordlist.csv, 1-based
+  line: usize,
+}
+
+fn get_integrated_words() -> (Vec<IntegratedWord>, Vec<ParseError>) {
   let mut rdr = csv::Reader::from_string(include_str!("integrated_wordlist.csv"))
                 .has_headers(false);
   let mut rv = Vec::new();
-  for row in rdr.decode() {
-    let (simp, level, lesson): (String, u32, u32) = row.unwrap();
-    if dict.search_simp(&simp).len() == 0 {
-      println!("{} not in dict", simp);
+  let mut errors = Vec::new();
+  // the word list has no header and no quoted newlines, so row i is on line i + 1
+  for (i, row) in rdr.decode::<(String, u32, u32)>().enumerate() {
+    match row {
+      Ok((simp, level, lesson)) => {
+        rv.push(IntegratedWord{simp: simp, level: level, lesson: lesson, line: i + 1});
+      },
+      Err(e) => {
+        errors.push(ParseError::new(
+            WORDLIST_PATH, Some(i + 1), ErrorKind::MalformedWordListLine(e.to_string())));
+      },
+    }
+  }
+  (rv, errors)
+}
+
+pub fn preferred_entry_keys() -> Vec<String> {
+  // the keys that the words on the list are looked up by in preferred_entries.yaml
+  get_integrated_words().0.into_iter().map(|word| word.simp).collect()
+}
+
+pub fn get_dict(cedict: &Vec<cedict::Entry>) -> (cedict::Dict, Vec<ParseError>) {
+  // CC-CEDICT plus the entries in extra_dict_integrated.txt, and the problems found parsing the
+  // latter
+  let (extra_entries, errors) = cedict::parse_entries_from(EXTRA_DICT, EXTRA_DICT_PATH);
+  (cedict::Dict::new_with_extra_entries(cedict, extra_entries), errors)
+}
+
+pub fn get_chinese_notes(dict: &cedict::Dict, preferred_overrides: &Vec<(String, String)>)
+    -> (Vec<chinese_note::ChineseNote>, Vec<ParseError>) {
+  // dict is from get_dict; preferred_overrides are --preferred_entries files (see
+  // PreferredEntryGetter::new)
+  let (preferred, mut errors) =
+      preferred_entry::PreferredEntryGetter::new(dict, preferred_overrides);
+  let (words, wordlist_errors) = get_integrated_words();
+  errors.extend(wordlist_errors.into_iter());
+  let mut rv = Vec::new();
+  for word in words {
+    if dict.search_simp(&word.simp).len() == 0 {
+      errors.push(
+          ParseError::new(WORDLIST_PATH, Some(word.line), ErrorKind::NotInDict(word.simp)));
       continue;
     }
-    let ce = preferred.get(&simp, None);
+    let (ce, other_entries, choice, problem) = preferred.choose(&word.simp, None);
+    match problem {
+      Some(kind) => { errors.push(ParseError::new(WORDLIST_PATH, Some(word.line), kind)); },
+      None => (),
+    }
     rv.push(chinese_note::ChineseNote{
         ce: ce,
-        tags: vec!(format!("IC_{}_{}", level, lesson)),
+        other_entries: other_entries,
+        tags: vec!(format!("IC_{}_{}", word.level, word.lesson)),
+        subdeck: Some(format!("L{}::Lesson {:02}", word.level, word.lesson)),
+        choice: choice,
     });
   }
-  rv
+  (rv, errors)
 }
@@ -0,0 +1,145 @@
+extern crate getopts;
+
+use cedict;
+use cedict_index;
+use hsk;
+use integrated;
+use parse_error::{ErrorKind, ParseError};
+use preferred_entry;
+use std;
+use std::io::Read;
+use std::path::Path;
+
+// The lint subcommand: checks the preferred entries and the extra dictionary files against
+// CC-CEDICT and the word lists, e.g. after updating the dictionary.
+
+fn print_usage(program: &str, opts: getopts::Options) {
+  let brief = format!(concat!(
+      "Usage: {} lint [options]\n\n",
+      "Checks src/preferred_entries.yaml, any --preferred_entries files,\n",
+      "src/extra_dict.txt and src/extra_dict_integrated.txt, and prints their problems:\n",
+      "preferred entries for words that aren't on the HSK or Integrated Chinese word\n",
+      "lists, preferred entries that don't match any dictionary entry, or that pick the\n",
+      "same entry as another one for the word, lines of the extra dictionaries that can't\n",
+      "be parsed, and extra entries that have the same headword and pinyin as a CC-CEDICT\n",
+      "entry. Fails if there are any."), program);
+  print!("{}", opts.usage(&brief));
+}
+
+fn lint_extra_dict(text: &str, path: &str, dict: &cedict::Dict) -> Vec<ParseError> {
+  // text is the contents of the extra dictionary at path
+  let (_, mut errors) = cedict::parse_entries_from(text, path);
+  for (i, line) in text.split("\n").enumerate() {
+    for entry in cedict::parse_entries(line) {
+      let params = cedict::DictSearchParams{
+          simp: Some(&entry.simp), trad: Some(&entry.trad), pinyin: Some(&entry.pinyin)};
+      // search ignores the case of pinyin, but [Gan1] and [gan1] are different entries
+      if dict.search(params).iter().any(|e| e.pinyin == entry.pinyin) {
+        errors.push(ParseError::new(
+            path, Some(i + 1), ErrorKind::ShadowingEntry(cedict::format_entry(&entry))));
+      }
+    }
+  }
+  errors.sort_by(|a, b| a.line.cmp(&b.line));
+  errors
+}
+
+fn lint(cedict_entries: &Vec<cedict::Entry>, preferred_overrides: &Vec<(String, String)>)
+    -> Vec<ParseError> {
+  // preferred_overrides are the paths and contents of --preferred_entries files
+  let dict = cedict::Dict::from_entries(cedict_entries.clone());
+  let mut errors = lint_extra_dict(hsk::EXTRA_DICT, hsk::EXTRA_DICT_PATH, &dict);
+  errors.extend(
+      lint_extra_dict(integrated::EXTRA_DICT, integrated::EXTRA_DICT_PATH, &dict).into_iter());
+  // preferred entries can pick entries from either extra dictionary
+  let (mut extra_entries, _) = cedict::parse_entries_from(hsk::EXTRA_DICT, hsk::EXTRA_DICT_PATH);
+  let (integrated_entries, _) =
+      cedict::parse_entries_from(integrated::EXTRA_DICT, integrated::EXTRA_DICT_PATH);
+  extra_entries.extend(integrated_entries.into_iter());
+  let dict = cedict::Dict::new_with_extra_entries(cedict_entries, extra_entries);
+  let mut keys = hsk::preferred_entry_keys();
+  keys.extend(integrated::preferred_entry_keys().into_iter());
+  errors.extend(preferred_entry::lint(&dict, &keys, preferred_overrides).into_iter());
+  errors
+}
+
+pub fn main(program: &str, args: &[String]) {
+  let mut opts = getopts::Options::new();
+  opts.optopt(
+      "", "cedict",
+      concat!("Read the CC-CEDICT dictionary from PATH, which may be gzipped (default ",
+              "src/cedict_1_0_ts_utf-8_mdbg.txt)."),
+      "PATH");
+  opts.optopt(
+      "", "cedict_index",
+      "Cache the parsed dictionary in PATH (default: the --cedict path plus .index).",
+      "PATH");
+  opts.optmulti(
+      "", "preferred_entries",
+      concat!("Also check YAML_FILE, a file of preferred entries like the ones passed to the ",
+              "main program's --preferred_entries. May be given more than once."),
+      "YAML_FILE");
+  opts.optflag("h", "help", "Print this help menu");
+
+  let parsed_opts = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => { panic!(f.to_string()) },
+  };
+  if parsed_opts.opt_present("h") || !parsed_opts.free.is_empty() {
+    print_usage(program, opts);
+    return;
+  }
+  let cedict_path = parsed_opts.opt_str("cedict").unwrap_or(cedict::DEFAULT_PATH.to_string());
+  let cedict_index_path =
+      parsed_opts.opt_str("cedict_index").unwrap_or(cedict_path.clone() + ".index");
+  let cedict_entries =
+      match cedict_index::load_entries(Path::new(&cedict_path), Path::new(&cedict_index_path)) {
+    Ok((entries, _)) => entries,
+    Err(e) => { panic!("{}", e); },
+  };
+
+  let mut preferred_overrides = Vec::new();
+  for path in parsed_opts.opt_strs("preferred_entries") {
+    let mut contents = String::new();
+    match std::fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+      Ok(_) => (),
+      Err(e) => {
+        panic!("Could not open preferred_entries {}, or it was not unicode: {}", path, e);
+      },
+    }
+    preferred_overrides.push((path, contents));
+  }
+
+  let problems = lint(&cedict_entries, &preferred_overrides);
+  for problem in &problems {
+    println!("{}", problem);
+  }
+  if !problems.is_empty() {
+    panic!("Found {} problems", problems.len());
+  }
+  println!("No problems found");
+}
+
+#[cfg(test)]
+mod tests {
+  use cedict;
+
+  #[test]
+  fn test_lint_extra_dict() {
+    let dict = cedict::Dict::new(concat!(
+        "說 说 [shuo1] /to speak/\n",
+        "他們 他们 [ta1 men5] /they/\n"));
+    let problems = super::lint_extra_dict(
+        concat!(
+            "# extra entries\n",
+            "說 说 [shuo1] /to say/\n",
+            "說 说 [shui4] /to persuade/\n",
+            "他們 他们 ta1 men5 /they/\n"),
+        "extra.txt", &dict);
+    assert_eq!(
+        problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        vec!(concat!("extra.txt:2: 說 说 [shuo1] /to say/ has the same headword and pinyin as a ",
+                     "CC-CEDICT entry"),
+             "extra.txt:4: not a CC-CEDICT entry: 他們 他们 ta1 men5 /they/"));
+  }
+}
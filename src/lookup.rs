@@ -0,0 +1,146 @@
+extern crate getopts;
+
+use cedict;
+use cedict_index;
+use hsk;
+use std::path::Path;
+
+// The lookup subcommand: searches the dictionary from the command line, e.g. to check which entry
+// a word in a word list will pick up.
+
+fn print_usage(program: &str, opts: getopts::Options) {
+  let brief = format!(concat!(
+      "Usage: {} lookup [options] QUERY...\n\n",
+      "Prints the CC-CEDICT entries for each QUERY. A QUERY written in Chinese characters\n",
+      "is looked up as a simplified or traditional word; anything else is taken to be\n",
+      "pinyin, with tone numbers (ni3 hao3), tone marks (nǐ hǎo) or no tones at all\n",
+      "(nihao). Entries whose tones match the query are listed first. With --english,\n",
+      "QUERY is looked for in the entries' definitions instead, and with --prefix or\n",
+      "--contains, the words that start with or contain QUERY are listed."), program);
+  print!("{}", opts.usage(&brief));
+}
+
+fn is_hanzi(c: char) -> bool {
+  // CJK Unified Ideographs and Extension A
+  ('\u{4e00}' <= c && c <= '\u{9fff}') || ('\u{3400}' <= c && c <= '\u{4dbf}')
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SearchMode {
+  // a word in characters or pinyin
+  Word,
+  English,
+  Headword(cedict::HeadwordSearch),
+}
+
+fn find(dict: &cedict::Dict, query: &str, mode: SearchMode) -> Vec<cedict::Entry> {
+  if mode == SearchMode::English {
+    dict.search_english(query)
+  } else if let SearchMode::Headword(how) = mode {
+    dict.search_headwords(query, how)
+  } else if query.chars().any(is_hanzi) {
+    let mut rv = dict.search_simp(query);
+    let params = cedict::DictSearchParams{simp: None, trad: Some(query), pinyin: None};
+    for entry in dict.search(params) {
+      if !rv.contains(&entry) {
+        rv.push(entry);
+      }
+    }
+    rv
+  } else {
+    dict.search_pinyin(query)
+  }
+}
+
+pub fn main(program: &str, args: &[String]) {
+  let mut opts = getopts::Options::new();
+  opts.optopt(
+      "", "cedict",
+      concat!("Read the CC-CEDICT dictionary from PATH, which may be gzipped (default ",
+              "src/cedict_1_0_ts_utf-8_mdbg.txt)."),
+      "PATH");
+  opts.optopt(
+      "", "cedict_index",
+      "Cache the parsed dictionary in PATH (default: the --cedict path plus .index).",
+      "PATH");
+  opts.optflag(
+      "e", "english",
+      concat!("Search the definitions for QUERY, e.g. \"to borrow\". Entries defined as exactly ",
+              "QUERY come first, then ones whose definitions contain it as whole words, then ",
+              "ones that only contain it as part of a word."));
+  opts.optflag(
+      "", "prefix",
+      "List the words whose simplified or traditional form starts with QUERY, shortest first.");
+  opts.optflag(
+      "", "contains",
+      "List the words whose simplified or traditional form contains QUERY, shortest first.");
+  opts.optflag(
+      "", "by_level",
+      "List HSK words first, from the lowest level to the highest.");
+  opts.optflag("h", "help", "Print this help menu");
+
+  let parsed_opts = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => { panic!(f.to_string()) },
+  };
+  if parsed_opts.opt_present("h") || parsed_opts.free.is_empty() {
+    print_usage(program, opts);
+    return;
+  }
+  let mode = if parsed_opts.opt_present("english") {
+    SearchMode::English
+  } else if parsed_opts.opt_present("prefix") {
+    SearchMode::Headword(cedict::HeadwordSearch::Prefix)
+  } else if parsed_opts.opt_present("contains") {
+    SearchMode::Headword(cedict::HeadwordSearch::Contains)
+  } else {
+    SearchMode::Word
+  };
+  let levels = if parsed_opts.opt_present("by_level") { Some(hsk::get_levels()) } else { None };
+  let cedict_path = parsed_opts.opt_str("cedict").unwrap_or(cedict::DEFAULT_PATH.to_string());
+  let cedict_index_path =
+      parsed_opts.opt_str("cedict_index").unwrap_or(cedict_path.clone() + ".index");
+  let dict = match cedict_index::load_dict(Path::new(&cedict_path), Path::new(&cedict_index_path)) {
+    Ok(dict) => dict,
+    Err(e) => { panic!("{}", e); },
+  };
+
+  for (i, query) in parsed_opts.free.iter().enumerate() {
+    if parsed_opts.free.len() > 1 {
+      if i > 0 {
+        println!("");
+      }
+      println!("{}:", query);
+    }
+    let mut entries = find(&dict, query, mode);
+    match levels {
+      Some(ref levels) => hsk::sort_by_level(&mut entries, levels),
+      None => (),
+    }
+    if entries.is_empty() {
+      println!("No entries found");
+    }
+    for entry in &entries {
+      println!("{}", cedict::format_entry(entry));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use cedict;
+
+  #[test]
+  fn test_find() {
+    let dict = cedict::Dict::new(concat!(
+        "說 说 [shuo1] /to speak/\n",
+        "他們 他们 [ta1 men5] /they/\n"));
+    let found = |query| super::find(&dict, query, super::SearchMode::Word).iter()
+        .map(|e| cedict::format_entry(e)).collect::<Vec<_>>();
+    assert_eq!(found("說"), vec!("說 说 [shuo1] /to speak/"));
+    assert_eq!(found("说"), vec!("說 说 [shuo1] /to speak/"));
+    assert_eq!(found("tamen"), vec!("他們 他们 [ta1 men5] /they/"));
+    assert!(found("ta").is_empty());
+    assert_eq!(super::find(&dict, "speak", super::SearchMode::English), dict.search_simp("说"));
+  }
+}
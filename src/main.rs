@@ -9,17 +9,31 @@ extern crate rustc_serialize;
 extern crate yaml;
 
 mod anki;
+mod anki21;
+mod build_report;
 mod cedict;
+mod cedict_index;
 mod chinese_note;
+mod collection;
+mod deck_config;
 mod hanping;
 mod hsk;
 mod integrated;
+mod lint;
+mod lookup;
+mod migrate;
+mod note_match;
+mod note_types;
+mod parse_error;
 mod preferred_entry;
+mod protobuf;
+mod subtract;
 
 use crypto::digest::Digest;
 use std::ascii::AsciiExt;
 use std::collections::HashMap;
 use std::io::Read;
+use std::path::Path;
 
 fn guid_from_str(s : &str) -> String {
   let mut sha = crypto::sha2::Sha256::new();
@@ -143,14 +157,18 @@ fn test_prettify_pinyin() {
           " <span class=\"tone5\">ba</span>"));
 }
 
-fn make_defs_html(items: &Vec<&str>) -> String {
+fn make_defs_html(items: &Vec<String>) -> String {
   // doesn't perform any escaping
   let mut rv = "<ol>".to_string();
   let mut first = true;
   for item in items {
-    if item.starts_with("variant of ")
-       || item.starts_with("old variant of ")
-       || item.starts_with("also written ") {
+    // other ways of writing the word go in the Also Written field instead (see
+    // make_also_written_str), since they'd give away the answer on English cards
+    let is_other_form = match cedict::parse_cross_ref(item) {
+      Some(r) => r.kind == cedict::RefKind::Variant || r.kind == cedict::RefKind::AlsoWritten,
+      None => false,
+    };
+    if is_other_form {
       continue;
     }
     if first {
@@ -168,64 +186,222 @@ fn make_clfr_str(clfr: &cedict::Classifier, trad_first: bool) -> String {
    clfr.simp.to_string()
  } else {
    if trad_first {
-     clfr.trad.to_string() + "|" + clfr.simp
+     clfr.trad.to_string() + "|" + &clfr.simp
    } else {
-     clfr.simp.to_string() + "|" + clfr.trad
+     clfr.simp.to_string() + "|" + &clfr.trad
    }
  };
- char + "(" + &prettify_pinyin(clfr.pinyin) + ")"
+ char + "(" + &prettify_pinyin(&clfr.pinyin) + ")"
+}
+
+fn make_also_written_str(entry: &cedict::Entry, trad_first: bool) -> String {
+  // the other forms of the word that entry's definitions point to, e.g. "乾|干" for "variant of
+  // 乾|干[gan1]"
+  let mut rv : Vec<String> = Vec::new();
+  for r in entry.defs.iter().filter_map(|d| cedict::parse_cross_ref(d)) {
+    if r.kind != cedict::RefKind::Variant && r.kind != cedict::RefKind::AlsoWritten {
+      continue;
+    }
+    let word = if r.simp == r.trad {
+      r.simp.clone()
+    } else if trad_first {
+      r.trad.clone() + "|" + &r.simp
+    } else {
+      r.simp.clone() + "|" + &r.trad
+    };
+    if !rv.contains(&word) {
+      rv.push(word);
+    }
+  }
+  rv.connect(", ")
+}
+
+#[cfg(test)]
+#[test]
+fn test_also_written() {
+  let entry = cedict::parse_entries(
+      "乾 干 [gan1] /old variant of 乾|干[gan1]/dry/also written 乾|干/see 干燥[gan1 zao4]/\n")
+      .pop().unwrap();
+  assert_eq!(make_defs_html(&entry.defs), "<ol><li>dry</li> <li>see 干燥[gan1 zao4]</li></ol>");
+  assert_eq!(make_also_written_str(&entry, false), "干|乾");
+  assert_eq!(make_also_written_str(&entry, true), "乾|干");
+}
+
+fn make_senses_html(entries: &[&cedict::Entry]) -> String {
+  // a note with several entries gets a list of definitions for each, in the same order as the
+  // readings in the Pinyin field (see join_distinct)
+  if entries.len() == 1 {
+    return make_defs_html(&entries[0].defs);
+  }
+  entries.iter()
+      .map(|e| "<div class=\"sense\">".to_string() + &make_defs_html(&e.defs) + "</div>")
+      .collect::<Vec<_>>()
+      .connect(" ")
+}
+
+fn join_distinct<F: Fn(&cedict::Entry) -> Vec<String>>(entries: &[&cedict::Entry], f: F, sep: &str)
+    -> String {
+  // joins f of each of a note's entries, leaving out repeats and empty strings
+  let mut rv : Vec<String> = Vec::new();
+  for item in entries.iter().flat_map(|e| f(e).into_iter()) {
+    if item != "" && !rv.contains(&item) {
+      rv.push(item);
+    }
+  }
+  rv.connect(sep)
+}
+
+#[cfg(test)]
+#[test]
+fn test_senses() {
+  let entries = cedict::parse_entries(concat!(
+      "行 行 [xing2] /to walk/capable/\n",
+      "行 行 [hang2] /row/\n",
+      "隻 只 [zhi1] /CL:for birds/\n",
+      "只 只 [zhi3] /only/\n"));
+  let xing_hang = vec!(&entries[0], &entries[1]);
+  assert_eq!(make_senses_html(&xing_hang[..1]), "<ol><li>to walk</li> <li>capable</li></ol>");
+  assert_eq!(
+      make_senses_html(&xing_hang),
+      concat!("<div class=\"sense\"><ol><li>to walk</li> <li>capable</li></ol></div>",
+              " <div class=\"sense\"><ol><li>row</li></ol></div>"));
+  assert_eq!(join_distinct(&xing_hang, |e| vec!(e.pinyin.clone()), " / "), "xing2 / hang2");
+  let zhi = vec!(&entries[2], &entries[3]);
+  assert_eq!(join_distinct(&zhi, |e| vec!(e.trad.clone()), " / "), "隻 / 只");
+  assert_eq!(join_distinct(&xing_hang, |e| vec!(e.simp.clone()), " / "), "行");
+}
+
+fn word_guid(guid_prefix: &str, note: &chinese_note::ChineseNote) -> String {
+  guid_from_str(
+      &(guid_prefix.to_string()
+        + " " + &note.ce.simp
+        + " " + &note.ce.trad
+        + " " + &note.ce.pinyin))
+}
+
+fn character_guid(guid_prefix: &str, ch: &str) -> String {
+  guid_from_str(&(guid_prefix.to_string() + " character " + ch))
+}
+
+fn get_character_words(notes: &Vec<chinese_note::ChineseNote>) -> Vec<(String, Vec<String>)> {
+  // returns each character used in notes (in order of first appearance), along with the other
+  // words in notes that contain it
+  let mut rv : Vec<(String, Vec<String>)> = Vec::new();
+  let mut idxs = HashMap::<String, usize>::new();
+  for note in notes {
+    for c in note.ce.simp.chars() {
+      let ch = c.to_string();
+      if !idxs.contains_key(&ch) {
+        idxs.insert(ch.clone(), rv.len());
+        rv.push((ch.clone(), Vec::new()));
+      }
+      let words = &mut rv[*idxs.get(&ch).unwrap()].1;
+      if note.ce.simp != ch && !words.contains(&note.ce.simp.to_string()) {
+        words.push(note.ce.simp.to_string());
+      }
+    }
+  }
+  rv
+}
+
+fn make_words_str(words: &Vec<String>) -> String {
+  // separate items with en spaces, like the Words With Same Pinyin field
+  words.iter()
+      .take(10)
+      .map(|w| "<span class=\"nobr\">".to_string() + w + "</span>")
+      .collect::<Vec<_>>()
+      .connect("\u{2002}")
+}
+
+fn read_media_file(dir: &Path, stem: &str, exts: &[&str]) -> Option<(String, Vec<u8>)> {
+  // returns the filename and contents of the first file in dir named stem + "." + one of exts
+  for ext in exts {
+    let filename = format!("{}.{}", stem, ext);
+    let mut data = Vec::new();
+    match std::fs::File::open(dir.join(&filename)).and_then(|mut f| f.read_to_end(&mut data)) {
+      Ok(_) => { return Some((filename, data)); },
+      Err(_) => (),
+    }
+  }
+  None
+}
+
+fn report_problems(problems: &Vec<parse_error::ParseError>) {
+  // problems are lines of the dictionary or word list that had to be skipped, etc.; --strict
+  // fails the build once they've all been found (and written to --report)
+  for problem in problems {
+    println!("Warning: {}", problem);
+  }
 }
 
 fn print_usage(program: &str, opts: getopts::Options) {
   let brief = format!(concat!(
-      "Usage: {} [options]\n\n",
-      "By default, builds an Anki collection.anki2 SQLite3 database that includes\n",
-      "flashcards for the HSK word list. This database is written to\n",
-      "/tmp/collection.anki2."), program);
+      "Usage: {} [options]\n",
+      "       {} migrate [options] OLD NEW OUT\n",
+      "       {} subtract [options] A B OUT\n",
+      "       {} lookup [options] QUERY...\n",
+      "       {} lint [options]\n\n",
+      "By default, builds an Anki .apkg package that includes flashcards for the HSK\n",
+      "word list. The package is written to deck.apkg, or to the path given by\n",
+      "--output. The migrate subcommand copies progress from one deck to another, and\n",
+      "subtract removes one deck's words from another. The lookup subcommand searches\n",
+      "the dictionary by word or pinyin, and lint checks preferred_entries.yaml and the\n",
+      "extra dictionary files. Run them with --help for details."),
+      program, program, program, program, program);
   print!("{}", opts.usage(&brief));
 }
 
-fn get_pinyin_dupes<'a, 'b>(notes: &'a Vec<chinese_note::ChineseNote<'b>>)
-    -> HashMap<String, Vec<&'a cedict::Entry<'b>>> {
+fn entry_pinyins(entry: &cedict::Entry) -> Vec<String> {
+  // all the readings of entry (mainland, Taiwan and alternate), lowercased
+  let mut rv = vec!(entry.pinyin.to_ascii_lowercase());
+  for pinyin in Some(&entry.tw_pinyin).into_iter().chain(entry.alt_pinyin.iter()) {
+    let pinyin = pinyin.to_ascii_lowercase();
+    if pinyin != "" && !rv.contains(&pinyin) {
+      rv.push(pinyin);
+    }
+  }
+  rv
+}
+
+fn get_pinyin_dupes<'a>(notes: &'a Vec<chinese_note::ChineseNote>)
+    -> HashMap<String, Vec<&'a cedict::Entry>> {
   // returns map of pinyin (e.g. "duo1 me5") to words with that Pinyin
-  let mut rv = HashMap::<String, Vec<&'a cedict::Entry<'b>>>::new();
+  let mut rv = HashMap::<String, Vec<&'a cedict::Entry>>::new();
   for note in notes {
-    let mut pinyins = vec!(note.ce.pinyin.to_ascii_lowercase());
-    if note.ce.tw_pinyin != "" {
-      pinyins.push(note.ce.tw_pinyin.to_ascii_lowercase());
-    }
-    for pinyin in pinyins {
-      if rv.contains_key(&pinyin) {
-        rv.get_mut(&pinyin).unwrap().push(&note.ce);
-      } else {
-        let v = vec!(&note.ce);
-        rv.insert(pinyin, v);
+    for entry in note.entries() {
+      for pinyin in entry_pinyins(entry) {
+        if rv.contains_key(&pinyin) {
+          rv.get_mut(&pinyin).unwrap().push(entry);
+        } else {
+          let v = vec!(entry);
+          rv.insert(pinyin, v);
+        }
       }
     }
   }
   rv
 }
 
-fn get_pinyin_dupe_string_fn<'a, 'b>(
-    notes: &'a Vec<chinese_note::ChineseNote<'b>>, trad_first: bool)
-    -> Box<Fn(&cedict::Entry) -> String + 'a> {
+fn get_pinyin_dupe_string_fn<'a>(
+    notes: &'a Vec<chinese_note::ChineseNote>, trad_first: bool)
+    -> Box<Fn(&[&cedict::Entry]) -> String + 'a> {
+  // the returned function takes a note's entries
   let dupes_map = get_pinyin_dupes(&notes);
   // separate items with en spaces, to make them slightly easier to read
   let en_space = '\u{2002}';
-  Box::new(move |entry| {
+  Box::new(move |entries| {
     let mut first = true;
     let mut rv = "".to_string();
-    let mut pinyins = vec!(entry.pinyin.to_ascii_lowercase());
-    if entry.tw_pinyin != "" {
-      pinyins.push(entry.tw_pinyin.to_ascii_lowercase());
-    }
-    for pinyin in pinyins {
+    // a word can share more than one reading with the note, but is only listed once
+    let mut listed : Vec<&cedict::Entry> = Vec::new();
+    for pinyin in entries.iter().flat_map(|entry| entry_pinyins(entry).into_iter()) {
       if !dupes_map.contains_key(&pinyin) {
-        println!("warning: {} not in dupes_map", entry.pinyin.to_ascii_lowercase());
+        println!("warning: {} not in dupes_map", pinyin);
         return rv;
       }
       for dupe in dupes_map.get(&pinyin).unwrap() {
-        if **dupe == *entry { continue; }
+        if entries.contains(dupe) || listed.contains(dupe) { continue; }
+        listed.push(dupe);
         if !first {
           rv.push(en_space);
         }
@@ -252,6 +428,25 @@ fn get_pinyin_dupe_string_fn<'a, 'b>(
 }
 
 fn main() {
+  let args: Vec<String> = std::env::args().collect();
+  let program: String = args[0].clone();
+  if args.len() > 1 && args[1] == "migrate" {
+    migrate::main(&program, &args[2..]);
+    return;
+  }
+  if args.len() > 1 && args[1] == "subtract" {
+    subtract::main(&program, &args[2..]);
+    return;
+  }
+  if args.len() > 1 && args[1] == "lookup" {
+    lookup::main(&program, &args[2..]);
+    return;
+  }
+  if args.len() > 1 && args[1] == "lint" {
+    lint::main(&program, &args[2..]);
+    return;
+  }
+
   let mut opts = getopts::Options::new();
   // TODO: make this smart enough to handle all possible Hanping export formats (i.e. it shouldn't
   // matter whether the user has simp or trad as primary)
@@ -271,14 +466,108 @@ fn main() {
               "the CC-CEDICT dictionary. ENTRIES_FILE must be in CC-CEDICT format. Currently ",
               "ignored unless --hanping_words is passed."),
       "ENTRIES_FILE");
+  opts.optmulti(
+      "", "preferred_entries",
+      concat!("Pick the dictionary entries for words as YAML_FILE says, taking precedence over ",
+              "src/preferred_entries.yaml (and over earlier --preferred_entries files). Words ",
+              "can be picked by pinyin, trad and def (part of a definition), and defs and ",
+              "max_defs replace or trim their definitions. May be given more than once."),
+      "YAML_FILE");
+  opts.optopt(
+      "", "cedict",
+      concat!("Read the CC-CEDICT dictionary from PATH, which may be gzipped (default ",
+              "src/cedict_1_0_ts_utf-8_mdbg.txt)."),
+      "PATH");
+  opts.optopt(
+      "", "cedict_index",
+      concat!("Cache the parsed dictionary in PATH (default: the --cedict path plus .index), so ",
+              "that later runs start faster. The cache is rebuilt whenever the dictionary ",
+              "changes."),
+      "PATH");
   opts.optflag(
       "t", "traditional",
       concat!("Display traditional characters before simplified, and Taiwanese pronunciations ",
               "before mainland."));
+  opts.optflag(
+      "", "subdecks",
+      concat!("Put cards in a sub-deck for their HSK level or Integrated Chinese lesson (e.g. ",
+              "HSK::Level 3 or Integrated Chinese::L1::Lesson 04) instead of all in one deck."));
+  opts.optflag(
+      "", "character_notes",
+      concat!("Also add a note for each character that appears in the deck's words, using a ",
+              "separate single-character note type."));
+  opts.optopt(
+      "", "sentences",
+      concat!("Also add cloze notes for the sentences in FILE, using a separate sentence note ",
+              "type. Each line of FILE is a sentence using Anki's cloze syntax (e.g. ",
+              "我{{c1::喜欢}}喝茶。), optionally followed by a tab and a translation."),
+      "FILE");
+  opts.optopt(
+      "", "media_dir",
+      concat!("Look in DIR for pronunciation audio named SIMPLIFIED.mp3 and stroke order images ",
+              "named SIMPLIFIED.svg, SIMPLIFIED.png or SIMPLIFIED.gif, and include them on the ",
+              "cards."),
+      "DIR");
+  opts.optopt(
+      "", "deck_config",
+      concat!("Read deck options (new cards per day, learning steps, intervals, etc.) from the ",
+              "YAML mapping in FILE. See the README for the available keys."),
+      "FILE");
+  opts.optmulti(
+      "", "deck_option",
+      concat!("Set a single deck option, e.g. --deck_option new_per_day=10 or --deck_option ",
+              "'learning_steps=1 10 60'. Overrides --deck_config. May be given more than once."),
+      "KEY=VALUE");
+  opts.optopt(
+      "", "format",
+      concat!("Write the collection in FORMAT, which is either anki2 (the default; the legacy ",
+              "layout that every version of Anki can import) or anki21 (the layout used natively ",
+              "by Anki 2.1.50 and later)."),
+      "FORMAT");
+  opts.optopt(
+      "o", "output",
+      "Write the finished .apkg package to PATH instead of deck.apkg.",
+      "PATH");
+  opts.optopt(
+      "", "update",
+      concat!("Instead of building a new collection, update the one in PATH (an .apkg exported ",
+              "from Anki, or a collection.anki2 file). Notes are matched by GUID, so review ",
+              "history, scheduling, deck options and the learner's own tags are kept; changed ",
+              "notes are updated and new ones added. The result is written to --output. Can't be ",
+              "combined with --format, --deck_config or --deck_option."),
+      "PATH");
+  opts.optflag(
+      "", "remove_missing",
+      concat!("With --update, delete notes that use one of the deck's note types but are no ",
+              "longer generated, along with their cards. Notes for words skipped by ",
+              "--known_words are kept."));
+  opts.optopt(
+      "", "known_words",
+      concat!("Leave out words that are already in the Anki collection in PATH (a ",
+              "collection.anki2 file, or an .apkg exported from Anki), e.g. because the learner ",
+              "already studies them in another deck. Words are looked up by simplified and ",
+              "traditional characters."),
+      "PATH");
+  opts.optopt(
+      "", "known_field",
+      concat!("The field of the --known_words notes that holds the word's simplified ",
+              "characters (default Simplified). Notes without such a field are looked up by their ",
+              "sort field."),
+      "NAME");
+  opts.optflag(
+      "", "strict",
+      concat!("Fail instead of building the deck if any line of the dictionary, extra entries, ",
+              "word list, preferred entries or --sentences can't be parsed, or any word isn't in ",
+              "the dictionary. Normally these are reported as warnings and skipped. --report is ",
+              "still written."));
+  opts.optopt(
+      "", "report",
+      concat!("Write a report of the words that were left out, the words with several dictionary ",
+              "entries (and which one was used, and why), and the duplicate notes that were ",
+              "merged to PATH, as CSV if PATH ends in .csv and as JSON otherwise."),
+      "PATH");
   opts.optflag("h", "help", "Print this help menu");
 
-  let args: Vec<String> = std::env::args().collect();
-  let program: String = args[0].clone();
   let parsed_opts = match opts.parse(&args[1..]) {
     Ok(m) => m,
     Err(f) => { panic!(f.to_string()) },
@@ -288,102 +577,359 @@ fn main() {
     return;
   }
 
-  let mut extra_entries = String::new();
+  let mut extra_entries_text = String::new();
+  let extra_entries_path = parsed_opts.opt_str("extra_entries").unwrap_or(String::new());
   if parsed_opts.opt_present("extra_entries") {
-    match std::fs::File::open(parsed_opts.opt_str("extra_entries").unwrap())
-        .and_then(|mut f| f.read_to_string(&mut extra_entries)) {
+    match std::fs::File::open(&extra_entries_path)
+        .and_then(|mut f| f.read_to_string(&mut extra_entries_text)) {
       Ok(_) => (),
       Err(e) => {
         panic!("Could not open extra_entries, or it was not unicode: {}", e);
       },
     }
   }
+  let (extra_entries, mut problems) =
+      cedict::parse_entries_from(&extra_entries_text, &extra_entries_path);
+  let mut preferred_overrides = Vec::new();
+  for path in parsed_opts.opt_strs("preferred_entries") {
+    let mut contents = String::new();
+    match std::fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+      Ok(_) => (),
+      Err(e) => {
+        panic!("Could not open preferred_entries {}, or it was not unicode: {}", path, e);
+      },
+    }
+    preferred_overrides.push((path, contents));
+  }
 
+  let cedict_path = parsed_opts.opt_str("cedict").unwrap_or(cedict::DEFAULT_PATH.to_string());
+  let cedict_index_path =
+      parsed_opts.opt_str("cedict_index").unwrap_or(cedict_path.clone() + ".index");
+  let cedict_entries =
+      match cedict_index::load_entries(Path::new(&cedict_path), Path::new(&cedict_index_path)) {
+    Ok((entries, cedict_problems)) => {
+      problems.extend(cedict_problems.into_iter());
+      entries
+    },
+    Err(e) => { panic!("{}", e); },
+  };
+
+  // the dictionary is also used for the character notes, so it has the same extra entries as the
+  // one the words were looked up in
   let mut hanping_words = String::new();
-  let (notes, title, guid_prefix) = if parsed_opts.opt_present("hanping_words") {
-    match std::fs::File::open(parsed_opts.opt_str("hanping_words").unwrap())
+  let (dict, loaded, title, guid_prefix) = if parsed_opts.opt_present("hanping_words") {
+    let hanping_path = parsed_opts.opt_str("hanping_words").unwrap();
+    match std::fs::File::open(&hanping_path)
         .and_then(|mut f| f.read_to_string(&mut hanping_words)) {
       Ok(_) => (),
       Err(e) => {
         panic!("Could not open hanping_words, or it was not unicode: {}", e);
       }
     }
-    (
-        // TODO: this will silently drop lines in extra_entries if they're malformed
-        hanping::get_chinese_notes(&hanping_words, &extra_entries),
-        "Hanping",
-        "kerrick hanping",
-    )
+    let dict = cedict::Dict::new_with_extra_entries(&cedict_entries, extra_entries);
+    let loaded =
+        hanping::get_chinese_notes(&hanping_words, &hanping_path, &dict, &preferred_overrides);
+    (dict, loaded, "Hanping", "kerrick hanping")
   } else if parsed_opts.opt_present("integrated") {
-    (integrated::get_chinese_notes(), "Integrated Chinese", "kerrick integrated")
+    let (dict, dict_problems) = integrated::get_dict(&cedict_entries);
+    problems.extend(dict_problems.into_iter());
+    let loaded = integrated::get_chinese_notes(&dict, &preferred_overrides);
+    (dict, loaded, "Integrated Chinese", "kerrick integrated")
   } else {
-    (hsk::get_chinese_notes(), "HSK", "kerrick hsk")
+    let (dict, dict_problems) = hsk::get_dict(&cedict_entries);
+    problems.extend(dict_problems.into_iter());
+    let loaded = hsk::get_chinese_notes(&dict, &preferred_overrides);
+    (dict, loaded, "HSK", "kerrick hsk")
+  };
+  let (mut notes, notes_problems) = loaded;
+  problems.extend(notes_problems.into_iter());
+  report_problems(&problems);
+
+  let out_path = parsed_opts.opt_str("output").unwrap_or("deck.apkg".to_string());
+  // known words are left out before anything else looks at the notes, so that the report only
+  // covers the words that go into the deck
+  let mut skipped = Vec::new();
+  match parsed_opts.opt_str("known_words") {
+    Some(known_path) => {
+      let field = parsed_opts.opt_str("known_field").unwrap_or("Simplified".to_string());
+      let known = match note_match::known_headwords(
+          Path::new(&known_path), Path::new(&(out_path.clone() + ".known")), &field) {
+        Ok(known) => known,
+        Err(e) => { panic!("Could not read {}: {}", known_path, e); },
+      };
+      let (known_notes, other_notes) = notes.into_iter()
+          .partition(|note| known.contains(&note.ce.simp) || known.contains(&note.ce.trad));
+      notes = other_notes;
+      skipped = known_notes;
+      println!("Skipping {} words that are already in {}", skipped.len(), known_path);
+    },
+    None => (),
+  }
+  let (notes, merged) = chinese_note::merge_duplicates(notes);
+  let mut report = build_report::BuildReport::new(&problems, &notes);
+  for word in merged {
+    report.add_duplicate(&word);
+  }
+  for note in &skipped {
+    report.add_known(&note.ce.simp);
+  }
+
+  if parsed_opts.opt_present("update") {
+    // the collection being updated keeps its own format and deck options
+    for opt in &["format", "deck_config", "deck_option"] {
+      if parsed_opts.opt_present(opt) {
+        panic!("--{} can't be used with --update, which keeps the collection's own", opt);
+      }
+    }
+  }
+  let format = match parsed_opts.opt_str("format") {
+    Some(f) => match anki::Format::from_str(&f) {
+      Some(format) => format,
+      None => { panic!("Unknown --format {}; expected anki2 or anki21", f); },
+    },
+    None => anki::Format::Anki2,
   };
+  let mut deck_config = deck_config::DeckConfig::new();
+  if parsed_opts.opt_present("deck_config") {
+    let mut deck_config_yaml = String::new();
+    match std::fs::File::open(parsed_opts.opt_str("deck_config").unwrap())
+        .and_then(|mut f| f.read_to_string(&mut deck_config_yaml)) {
+      Ok(_) => (),
+      Err(e) => {
+        panic!("Could not open deck_config, or it was not unicode: {}", e);
+      },
+    }
+    match deck_config.set_from_yaml(&deck_config_yaml) {
+      Ok(_) => (),
+      Err(e) => { panic!("{}", e); },
+    }
+  }
+  for option in parsed_opts.opt_strs("deck_option") {
+    let mut pieces = option.splitn(2, "=");
+    let key = pieces.next().unwrap();
+    let result = match pieces.next() {
+      Some(value) => deck_config.set(key, value),
+      None => Err(format!("--deck_option {} is not of the form KEY=VALUE", option)),
+    };
+    match result {
+      Ok(_) => (),
+      Err(e) => { panic!("{}", e); },
+    }
+  }
 
-  let templates_yaml = include_str!("templates.yaml")
-      .replace("CHARACTER",
-               if parsed_opts.opt_present("traditional") {
-                 "{{#Traditional}}<span class=\"nobr\">{{Traditional}}</span>|{{/Traditional}}<span class=\"nobr\">{{Simplified}}</span>"
-               } else {
-                 "<span class=\"nobr\">{{Simplified}}</span>{{#Traditional}}|<span class=\"nobr\">{{Traditional}}</span>{{/Traditional}}"
-               })
-      .replace("PINYIN",
-               if parsed_opts.opt_present("traditional") {
-                 "{{#Taiwan Pinyin}}{{Taiwan Pinyin}} | {{/Taiwan Pinyin}}{{Pinyin}}"
-               } else {
-                 "{{Pinyin}}{{#Taiwan Pinyin}} | {{Taiwan Pinyin}}{{/Taiwan Pinyin}}"
-               });
-
-  let apkg = anki::AnkiPackage::new(
-      title, include_str!("flds.json"), &templates_yaml, include_str!("card.css"));
+  let trad_first = parsed_opts.opt_present("traditional");
+  let model = note_types::word_model(title, trad_first);
+  let mut apkg = match parsed_opts.opt_str("update") {
+    Some(in_path) => {
+      let opened = anki::AnkiPackage::open(Path::new(&in_path), Path::new(&out_path));
+      let mut apkg = match opened {
+        Ok(apkg) => apkg,
+        Err(e) => { panic!("Could not open {}: {}", in_path, e); },
+      };
+      apkg.add_top_level_deck(title);
+      apkg.add_model(&model);
+      apkg
+    },
+    None => anki::AnkiPackage::new(Path::new(&out_path), format, title, &model, &deck_config),
+  };
   let pinyin_not_hint = get_pinyin_dupe_string_fn(&notes, parsed_opts.opt_present("traditional"));
 
+  let media_dir = parsed_opts.opt_str("media_dir");
+
   for note in &notes {
-    let trad = if note.ce.simp != note.ce.trad { note.ce.trad } else { "" };
+    let entries = note.entries();
+    // notes with several entries have several traditional forms only if some entry has one that
+    // differs from the simplified
+    let trad = if entries.iter().any(|e| e.simp != e.trad) {
+      join_distinct(&entries, |e| vec!(e.trad.clone()), " / ")
+    } else {
+      String::new()
+    };
+    let mut audio = String::new();
+    let mut stroke_order = String::new();
+    match media_dir {
+      Some(ref dir) => {
+        let dir = Path::new(dir);
+        match read_media_file(dir, &note.ce.simp, &["mp3"]) {
+          Some((filename, data)) => {
+            audio = anki::sound_tag(&apkg.add_media(&filename, data));
+          },
+          None => (),
+        }
+        match read_media_file(dir, &note.ce.simp, &["svg", "png", "gif"]) {
+          Some((filename, data)) => {
+            stroke_order = anki::img_tag(&apkg.add_media(&filename, data));
+          },
+          None => (),
+        }
+      },
+      None => (),
+    }
+    let deck_id = match note.subdeck {
+      Some(ref subdeck) if parsed_opts.opt_present("subdecks") => apkg.add_subdeck(subdeck),
+      _ => anki::DECK_ID,
+    };
     let note_id = apkg.add_note(
-        &guid_from_str(
-            &(guid_prefix.to_string()
-              + " " + &note.ce.simp
-              + " " + &note.ce.trad
-              + " " + &note.ce.pinyin)),
+        &model,
+        &word_guid(guid_prefix, note),
         &(" ".to_string() + &note.tags.connect(" ") + " "),
         &(note.ce.simp.to_string()
             + "\x1f" + &trad
-            + "\x1f" + &prettify_pinyin(note.ce.pinyin)
-            + "\x1f" + &make_defs_html(&note.ce.defs)
-            + "\x1f" + &note.ce.clfrs.iter()
-                .map(|c| make_clfr_str(c, parsed_opts.opt_present("traditional")))
-                .collect::<Vec<_>>().connect(", ")
-            + "\x1f" + &prettify_pinyin(note.ce.tw_pinyin)
-            + "\x1f" + &pinyin_not_hint(&note.ce)),
-        &note.ce.simp);
-    apkg.add_card(note_id, 0);
+            + "\x1f" + &join_distinct(&entries, |e| vec!(prettify_pinyin(&e.pinyin)), " / ")
+            + "\x1f" + &make_senses_html(&entries)
+            + "\x1f" + &join_distinct(
+                &entries, |e| e.clfrs.iter().map(|c| make_clfr_str(c, trad_first)).collect(), ", ")
+            + "\x1f" + &prettify_pinyin(&note.ce.tw_pinyin)
+            + "\x1f" + &pinyin_not_hint(&entries)
+            + "\x1f" + &audio
+            + "\x1f" + &stroke_order
+            + "\x1f" + &join_distinct(
+                &entries, |e| vec!(make_also_written_str(e, trad_first)), ", ")
+            + "\x1f" + &join_distinct(
+                &entries,
+                |e| e.alt_pinyin.iter()
+                    .filter(|p| !entries.iter().any(|other| other.pinyin == **p))
+                    .map(|p| prettify_pinyin(p))
+                    .collect(),
+                ", ")));
+    apkg.add_card(note_id, deck_id, 0);
     if trad == "" {
-      apkg.add_card(note_id, 1);
+      apkg.add_card(note_id, deck_id, 1);
     } else {
       if parsed_opts.opt_present("traditional") {
-        apkg.add_card(note_id, 2);
-        apkg.add_card(note_id, 1);
+        apkg.add_card(note_id, deck_id, 2);
+        apkg.add_card(note_id, deck_id, 1);
       } else {
-        apkg.add_card(note_id, 1);
-        apkg.add_card(note_id, 2);
+        apkg.add_card(note_id, deck_id, 1);
+        apkg.add_card(note_id, deck_id, 2);
+      }
+    }
+    apkg.add_card(note_id, deck_id, 3);
+  }
+  if parsed_opts.opt_present("character_notes") {
+    let character_model = note_types::character_model(title, trad_first);
+    apkg.add_model(&character_model);
+    let deck_id = if parsed_opts.opt_present("subdecks") {
+      apkg.add_subdeck("Characters")
+    } else {
+      anki::DECK_ID
+    };
+    let (preferred, preferred_problems) =
+        preferred_entry::PreferredEntryGetter::new(&dict, &preferred_overrides);
+    // the word list loaders have usually reported these already
+    let mut character_problems : Vec<parse_error::ParseError> =
+        preferred_problems.into_iter().filter(|p| !problems.contains(p)).collect();
+    for (ch, words) in get_character_words(&notes) {
+      if dict.search_simp(&ch).len() == 0 {
+        character_problems.push(parse_error::ParseError::new(
+            &cedict_path, None, parse_error::ErrorKind::NotInDict(ch.clone())));
+        continue;
+      }
+      let (ce, problem) = preferred.get(&ch, None);
+      match problem {
+        Some(kind) => {
+          character_problems.push(parse_error::ParseError::new(&cedict_path, None, kind));
+        },
+        None => (),
       }
+      let trad = if ce.simp != ce.trad { &ce.trad as &str } else { "" };
+      let note_id = apkg.add_note(
+          &character_model,
+          &character_guid(guid_prefix, &ch),
+          "",
+          &(ce.simp.to_string()
+              + "\x1f" + &trad
+              + "\x1f" + &prettify_pinyin(&ce.pinyin)
+              + "\x1f" + &prettify_pinyin(&ce.tw_pinyin)
+              + "\x1f" + &make_defs_html(&ce.defs)
+              + "\x1f" + &make_words_str(&words)));
+      apkg.add_card(note_id, deck_id, 0);
     }
-    apkg.add_card(note_id, 3);
+    report_problems(&character_problems);
+    for problem in &character_problems {
+      report.add_problem(problem);
+    }
+    problems.extend(character_problems.into_iter());
   }
-  // Set due = id + 1
-  apkg.conn.execute_batch("UPDATE cards SET due = id + 1;").unwrap();
 
-  // Kill duplicate notes: 等, 对, 过, 花 each only have one entry in CC-CEDICT
-  for row in apkg.conn.prepare(
-      concat!(
-          "select a.id, a.sfld",
-          " from notes as a join notes as b",
-          " on a.flds == b.flds where a.id > b.id"))
-      .unwrap().query(&[]).unwrap().map(|row| row.unwrap()) {
-    let note_id : i64 = row.get(0);
-    // println!("deleting {}", row.get::<String>(1));
-    apkg.conn.execute("delete from cards where nid == ?", &[&note_id]).unwrap();
-    apkg.conn.execute("delete from notes where id == ?", &[&note_id]).unwrap();
+  if parsed_opts.opt_present("sentences") {
+    let sentences_path = parsed_opts.opt_str("sentences").unwrap();
+    let mut sentences = String::new();
+    match std::fs::File::open(&sentences_path)
+        .and_then(|mut f| f.read_to_string(&mut sentences)) {
+      Ok(_) => (),
+      Err(e) => {
+        panic!("Could not open sentences, or it was not unicode: {}", e);
+      },
+    }
+    let cloze_model = note_types::cloze_model(title);
+    apkg.add_model(&cloze_model);
+    let deck_id = if parsed_opts.opt_present("subdecks") {
+      apkg.add_subdeck("Sentences")
+    } else {
+      anki::DECK_ID
+    };
+    let mut sentence_problems = Vec::new();
+    for (i, line) in sentences.lines().enumerate().filter(|&(_, l)| l.trim() != "") {
+      let mut pieces = line.splitn(2, "\t");
+      let text = pieces.next().unwrap();
+      let translation = pieces.next().unwrap_or("");
+      let ords = note_types::cloze_ords(text);
+      if ords.len() == 0 {
+        sentence_problems.push(parse_error::ParseError::new(
+            &sentences_path, Some(i + 1),
+            parse_error::ErrorKind::MalformedSentence(line.to_string())));
+        continue;
+      }
+      let note_id = apkg.add_note(
+          &cloze_model,
+          &guid_from_str(&(guid_prefix.to_string() + " sentence " + text)),
+          "",
+          &(text.to_string() + "\x1f" + translation));
+      for ord in ords {
+        apkg.add_card(note_id, deck_id, ord);
+      }
+    }
+    report_problems(&sentence_problems);
+    for problem in &sentence_problems {
+      report.add_problem(problem);
+    }
+    problems.extend(sentence_problems.into_iter());
+  }
+
+  if parsed_opts.opt_present("remove_missing") {
+    // words skipped by --known_words (and their characters) are still part of the deck, so
+    // existing notes for them keep their review history
+    for note in &skipped {
+      apkg.keep_note(&word_guid(guid_prefix, note));
+    }
+    if parsed_opts.opt_present("character_notes") {
+      for (ch, _) in get_character_words(&skipped) {
+        apkg.keep_note(&character_guid(guid_prefix, &ch));
+      }
+    }
+    apkg.remove_missing_notes();
+  }
+
+  match parsed_opts.opt_str("report") {
+    Some(report_path) => match report.write(Path::new(&report_path)) {
+      Ok(_) => (),
+      Err(e) => { panic!("Could not write report {}: {}", report_path, e); },
+    },
+    None => (),
+  }
+  if parsed_opts.opt_present("strict") && !problems.is_empty() {
+    match apkg.discard() {
+      Ok(_) => (),
+      Err(e) => { println!("Warning: could not clean up after building {}: {}", out_path, e); },
+    }
+    panic!("Found {} problems in the dictionary or word list, and --strict was given",
+           problems.len());
+  }
+  match apkg.write() {
+    Ok(_) => (),
+    Err(e) => {
+      panic!("Could not write {}: {}", out_path, e);
+    },
   }
 }
@@ -0,0 +1,192 @@
+extern crate getopts;
+
+use anki;
+use note_match;
+use std::collections::HashMap;
+use std::path::Path;
+
+// The migrate subcommand: copies the learner's progress (scheduling, review counts, etc.) from an
+// old deck into a newly generated one, for when the new deck can't simply be imported over the old
+// one (e.g. because the old deck came from somewhere else).
+
+fn print_usage(program: &str, opts: getopts::Options) {
+  let brief = format!(concat!(
+      "Usage: {} migrate [options] OLD NEW OUT\n\n",
+      "Copies card progress from the deck in OLD to the matching cards of the deck in NEW,\n",
+      "and writes the result to OUT. OLD and NEW are .apkg packages (or bare collection\n",
+      "files); OUT may be the same as NEW. Cards are matched by the note they belong to\n",
+      "and by the name of their card type."), program);
+  print!("{}", opts.usage(&brief));
+}
+
+fn parse_template_map(specs: &[String]) -> Result<HashMap<String, Vec<String>>, String> {
+  // each spec is OLD=NEW; an OLD that appears more than once maps to several new card types
+  let mut rv : HashMap<String, Vec<String>> = HashMap::new();
+  for spec in specs {
+    let mut pieces = spec.splitn(2, "=");
+    let old = pieces.next().unwrap();
+    match pieces.next() {
+      Some(new) => rv.entry(old.to_string()).or_insert(Vec::new()).push(new.to_string()),
+      None => { return Err(format!("--template_map {} is not of the form OLD=NEW", spec)); },
+    }
+  }
+  Ok(rv)
+}
+
+fn is_day_based(schedule: &anki::Schedule) -> bool {
+  // whether due is a day number rather than a position or timestamp: cards in review (queue 2),
+  // cards in day-spanning learning (queue 3), and suspended/buried review cards
+  schedule.queue == 2 || schedule.queue == 3 || (schedule.queue < 0 && schedule.card_type == 2)
+}
+
+pub fn main(program: &str, args: &[String]) {
+  let mut opts = getopts::Options::new();
+  opts.optopt(
+      "", "match",
+      concat!("How notes in OLD are matched to notes in NEW: guid (the default; for decks built ",
+              "by this program), headword (the Simplified field, or the sort field of note types ",
+              "without one), identity (like headword, but Traditional and Pinyin must match too) ",
+              "or field:NAME (the field called NAME)."),
+      "MATCH");
+  opts.optmulti(
+      "", "template_map",
+      concat!("Copy the progress of OLD's cards of type OLD_TMPL to NEW's cards of type NEW_TMPL. ",
+              "OLD_TMPL may also be a card type's index. By default, card types are matched by ",
+              "name. May be given more than once, including several times for the same OLD_TMPL, ",
+              "e.g. --template_map 'Chinese Character=Simplified' --template_map ",
+              "'Chinese Character=Traditional'."),
+      "OLD_TMPL=NEW_TMPL");
+  opts.optflag("h", "help", "Print this help menu");
+
+  let parsed_opts = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => { panic!(f.to_string()) },
+  };
+  if parsed_opts.opt_present("h") || parsed_opts.free.len() != 3 {
+    print_usage(program, opts);
+    return;
+  }
+  let (old_path, new_path, out_path) =
+      (&parsed_opts.free[0], &parsed_opts.free[1], &parsed_opts.free[2]);
+  let match_by = match parsed_opts.opt_str("match") {
+    Some(m) => match note_match::MatchBy::from_str(&m) {
+      Some(match_by) => match_by,
+      None => { panic!("Unknown --match {}; expected guid, headword, identity or field:NAME", m); },
+    },
+    None => note_match::MatchBy::Guid,
+  };
+  let template_map = match parse_template_map(&parsed_opts.opt_strs("template_map")) {
+    Ok(template_map) => template_map,
+    Err(e) => { panic!("{}", e); },
+  };
+
+  // OLD is only read from, so its working copy goes next to OUT under a different name
+  let old = match anki::AnkiPackage::open_read_only(
+      Path::new(old_path), Path::new(&(out_path.to_string() + ".old"))) {
+    Ok(apkg) => apkg,
+    Err(e) => { panic!("Could not open {}: {}", old_path, e); },
+  };
+  let new = match anki::AnkiPackage::open(Path::new(new_path), Path::new(out_path)) {
+    Ok(apkg) => apkg,
+    Err(e) => { panic!("Could not open {}: {}", new_path, e); },
+  };
+
+  let old_models : HashMap<i64, anki::ModelSummary> =
+      old.models().into_iter().map(|m| (m.id, m)).collect();
+  let old_notes : HashMap<i64, anki::Note> = old.notes().into_iter().map(|n| (n.id, n)).collect();
+  let new_models : HashMap<i64, anki::ModelSummary> =
+      new.models().into_iter().map(|m| (m.id, m)).collect();
+  let new_note_mids : HashMap<i64, i64> =
+      new.notes().into_iter().map(|n| (n.id, n.mid)).collect();
+  let new_index = note_match::NoteIndex::new(&new, &match_by);
+  let new_cards : HashMap<(i64, i64), i64> =
+      new.cards().into_iter().map(|c| ((c.nid, c.ord), c.id)).collect();
+  // review due dates are counted in days from the collection's creation
+  let day_offset = (old.creation_time() - new.creation_time()) / 86400;
+
+  // if several old cards map to the same new card, the one that was reviewed most wins
+  let mut schedules : HashMap<i64, anki::Schedule> = HashMap::new();
+  let mut unmatched = Vec::new();
+  for card in old.cards() {
+    if card.schedule.card_type == 0 && card.schedule.reps == 0 {
+      // never studied, so there's nothing to copy
+      continue;
+    }
+    let note = &old_notes[&card.nid];
+    let model = old_models.get(&note.mid);
+    let tmpl_name = model
+        .and_then(|m| m.tmpls.get(card.ord as usize))
+        .map(|name| name.clone())
+        .unwrap_or(card.ord.to_string());
+    let new_tmpl_names = template_map.get(&tmpl_name)
+        .or(template_map.get(&card.ord.to_string()))
+        .map(|names| names.clone())
+        .unwrap_or(vec!(tmpl_name.clone()));
+
+    let key = note_match::note_key(&match_by, note, model);
+    let mut targets = Vec::new();
+    for nid in new_index.find(&key) {
+      let new_tmpls = &new_models[&new_note_mids[&nid]].tmpls;
+      for new_tmpl_name in &new_tmpl_names {
+        match new_tmpls.iter().position(|t| t == new_tmpl_name) {
+          Some(ord) => match new_cards.get(&(nid, ord as i64)) {
+            Some(&card_id) => targets.push(card_id),
+            None => (),
+          },
+          None => (),
+        }
+      }
+    }
+    if targets.is_empty() {
+      unmatched.push(format!("{} ({})", note.sfld, tmpl_name));
+      continue;
+    }
+
+    let mut schedule = card.schedule;
+    if is_day_based(&schedule) {
+      schedule.due += day_offset;
+    }
+    for card_id in targets {
+      let replace = match schedules.get(&card_id) {
+        Some(existing) => existing.reps < schedule.reps,
+        None => true,
+      };
+      if replace {
+        schedules.insert(card_id, schedule);
+      }
+    }
+  }
+
+  for (card_id, schedule) in &schedules {
+    new.set_schedule(*card_id, schedule);
+  }
+  println!("Copied progress to {} cards", schedules.len());
+  if !unmatched.is_empty() {
+    println!("{} studied cards in {} had no match in {}:", unmatched.len(), old_path, new_path);
+    for card in &unmatched {
+      println!("  {}", card);
+    }
+  }
+
+  match old.discard() {
+    Ok(_) => (),
+    Err(e) => { panic!("Could not clean up after reading {}: {}", old_path, e); },
+  }
+  match new.write() {
+    Ok(_) => (),
+    Err(e) => { panic!("Could not write {}: {}", out_path, e); },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn test_parse_template_map() {
+    let specs = vec!("0=Simplified".to_string(), "0=Traditional".to_string(),
+                     "English=English".to_string());
+    let template_map = super::parse_template_map(&specs).unwrap();
+    assert_eq!(template_map["0"], vec!("Simplified", "Traditional"));
+    assert_eq!(template_map["English"], vec!("English"));
+    assert!(super::parse_template_map(&["Simplified".to_string()]).is_err());
+  }
+}
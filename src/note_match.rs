@@ -0,0 +1,217 @@
+use anki;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+
+// How notes in two different collections are recognized as the same word, for the subcommands
+// that compare decks (migrate, subtract).
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum MatchBy {
+  // the note GUID, which stays the same from one build of a deck to the next
+  Guid,
+  // the Simplified field alone; for note types that don't have one (e.g. shared decks from other
+  // authors), the sort field is taken to be the simplified word
+  Headword,
+  // like Headword, but the Traditional and Pinyin fields must match too when both notes have them
+  Identity,
+  // the field with the given name, or the sort field for note types that don't have it
+  Field(String),
+}
+
+impl MatchBy {
+  pub fn from_str(s: &str) -> Option<MatchBy> {
+    match s {
+      "guid" => Some(MatchBy::Guid),
+      "headword" => Some(MatchBy::Headword),
+      "identity" => Some(MatchBy::Identity),
+      _ if s.starts_with("field:") => Some(MatchBy::Field(s["field:".len()..].to_string())),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct NoteKey {
+  // the GUID or the simplified word
+  pub primary: String,
+  // None if the note type has no such field; an empty traditional field means the word is written
+  // the same way in both scripts
+  pub trad: Option<String>,
+  pub pinyin: Option<String>,
+}
+
+impl NoteKey {
+  pub fn matches(&self, other: &NoteKey) -> bool {
+    // fields that only one of the notes has are ignored
+    fn compatible(a: &Option<String>, b: &Option<String>) -> bool {
+      match (a, b) {
+        (&Some(ref a), &Some(ref b)) => a == b,
+        _ => true,
+      }
+    }
+    self.primary == other.primary && compatible(&self.trad, &other.trad)
+        && compatible(&self.pinyin, &other.pinyin)
+  }
+}
+
+fn normalize_pinyin(s: &str) -> String {
+  anki::strip_html(s).to_lowercase().chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+pub fn note_key(match_by: &MatchBy, note: &anki::Note, model: Option<&anki::ModelSummary>)
+    -> NoteKey {
+  let field = |name: &str| -> Option<String> {
+    model
+        .and_then(|m| m.flds.iter().position(|f| f == name))
+        .and_then(|i| note.flds.get(i))
+        .map(|f| anki::strip_html(f))
+  };
+  let key = |primary: String| NoteKey { primary: primary, trad: None, pinyin: None };
+  let sort_field = || anki::strip_html(&note.sfld);
+
+  match *match_by {
+    MatchBy::Guid => key(note.guid.clone()),
+    MatchBy::Headword => key(field("Simplified").unwrap_or_else(sort_field)),
+    MatchBy::Identity => match field("Simplified") {
+      Some(simp) => {
+        let trad = field("Traditional").map(|trad| if trad == simp { String::new() } else { trad });
+        NoteKey {
+          primary: simp,
+          trad: trad,
+          pinyin: field("Pinyin").map(|pinyin| normalize_pinyin(&pinyin)),
+        }
+      },
+      None => key(sort_field()),
+    },
+    MatchBy::Field(ref name) => key(field(name).unwrap_or_else(sort_field)),
+  }
+}
+
+// The notes of a collection, looked up by key.
+pub struct NoteIndex {
+  by_primary: HashMap<String, Vec<(NoteKey, i64)>>,
+}
+
+impl NoteIndex {
+  pub fn new(apkg: &anki::AnkiPackage, match_by: &MatchBy) -> NoteIndex {
+    let models : HashMap<i64, anki::ModelSummary> =
+        apkg.models().into_iter().map(|m| (m.id, m)).collect();
+    let mut rv = NoteIndex { by_primary: HashMap::new() };
+    for note in apkg.notes() {
+      let key = note_key(match_by, &note, models.get(&note.mid));
+      rv.by_primary.entry(key.primary.clone()).or_insert(Vec::new()).push((key, note.id));
+    }
+    rv
+  }
+
+  pub fn find(&self, key: &NoteKey) -> Vec<i64> {
+    // IDs of the notes that match key
+    match self.by_primary.get(&key.primary) {
+      Some(notes) => {
+        notes.iter().filter(|&&(ref k, _)| k.matches(key)).map(|&(_, id)| id).collect()
+      },
+      None => Vec::new(),
+    }
+  }
+}
+
+pub fn known_headwords(path: &Path, scratch_path: &Path, field: &str)
+    -> io::Result<HashSet<String>> {
+  // reads the collection (or .apkg) at path, and returns the contents of each note's field called
+  // field, plus its Traditional field if it has one; for note types without field, the sort field
+  // is used instead. scratch_path is where the working copy of the collection is put.
+  let apkg = try!(anki::AnkiPackage::open_read_only(path, scratch_path));
+  let models : HashMap<i64, anki::ModelSummary> =
+      apkg.models().into_iter().map(|m| (m.id, m)).collect();
+  let mut rv = HashSet::new();
+  for note in apkg.notes() {
+    let model = models.get(&note.mid);
+    for name in &[field, "Traditional"] {
+      let key = note_key(&MatchBy::Field(name.to_string()), &note, model);
+      if key.primary != "" {
+        rv.insert(key.primary);
+      }
+    }
+  }
+  try!(apkg.discard());
+  Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anki;
+  use anki::tests::scratch_path;
+  use deck_config;
+  use note_types;
+  use std;
+
+  fn note(flds: &[&str]) -> anki::Note {
+    anki::Note {
+      id: 1,
+      guid: "abc".to_string(),
+      mid: 2,
+      tags: String::new(),
+      flds: flds.iter().map(|f| f.to_string()).collect(),
+      sfld: anki::strip_html(flds[0]),
+    }
+  }
+
+  #[test]
+  fn test_note_key() {
+    let model = anki::ModelSummary {
+      id: 2,
+      name: "HSK".to_string(),
+      flds: vec!("Simplified".to_string(), "Traditional".to_string(), "Pinyin".to_string()),
+      tmpls: Vec::new(),
+    };
+    let ours = note_key(&MatchBy::Identity, &note(&["<b>你好</b>", "你好", "Nǐ hǎo"]), Some(&model));
+    assert_eq!(ours.primary, "你好");
+    assert_eq!(ours.trad, Some(String::new()));
+    assert_eq!(ours.pinyin, Some("nǐhǎo".to_string()));
+
+    // a note type without a Simplified field is matched on its sort field alone
+    let theirs = note_key(&MatchBy::Identity, &note(&["你好", "hello"]), None);
+    assert!(theirs.matches(&ours));
+    let other = note_key(&MatchBy::Identity, &note(&["你好", "", "nǐhào"]), Some(&model));
+    assert!(!other.matches(&ours));
+
+    assert_eq!(note_key(&MatchBy::Guid, &note(&["你好"]), None).primary, "abc");
+  }
+
+  #[test]
+  fn test_match_by_from_str() {
+    assert_eq!(MatchBy::from_str("headword"), Some(MatchBy::Headword));
+    assert_eq!(MatchBy::from_str("field:English"), Some(MatchBy::Field("English".to_string())));
+    assert_eq!(MatchBy::from_str("sfld"), None);
+  }
+
+  #[test]
+  fn test_known_headwords_of_learner_collection() {
+    let path = scratch_path("known_headwords.apkg");
+    let model = note_types::word_model("HSK", false);
+    let mut apkg = anki::AnkiPackage::new(
+        &path, anki::Format::Anki21, "HSK", &model, &deck_config::DeckConfig::new());
+    let mut flds = vec!("说", "說");
+    flds.resize(model.flds.len(), "");
+    let note_id = apkg.add_note(&model, "a", "", &flds.connect("\x1f"));
+    apkg.add_card(note_id, anki::DECK_ID, 0);
+    // make it look like a collection created by Anki: it doesn't have our deck, and its indexes on
+    // names use a collation that SQLite doesn't know about
+    apkg.conn.execute("DELETE FROM decks WHERE id = ?", &[&anki::DECK_ID]).unwrap();
+    apkg.conn.execute_batch(concat!(
+        "PRAGMA writable_schema = ON;",
+        "UPDATE sqlite_master SET sql = replace(sql, '(name', '(name COLLATE unicase') ",
+        "WHERE name IN ('idx_decks_name', 'idx_notetypes_name');",
+        "PRAGMA writable_schema = OFF;")).unwrap();
+    apkg.write().unwrap();
+
+    let known = known_headwords(&path, &scratch_path("known_headwords_scratch"), "Simplified")
+        .unwrap();
+    let mut known : Vec<String> = known.into_iter().collect();
+    known.sort();
+    assert_eq!(known, vec!("說", "说"));
+    std::fs::remove_file(&path).unwrap();
+  }
+}
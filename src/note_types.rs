@@ -0,0 +1,103 @@
+use anki;
+use collection;
+
+// The note types that the generator can emit. Their names are derived from the deck title, since
+// note type names must be unique within a collection.
+
+pub const CHARACTER_MODEL_ID : i64 = 1425274727597;
+pub const CLOZE_MODEL_ID : i64 = 1425274727598;
+
+fn expand_placeholders(tmpls_yaml: &str, trad_first: bool) -> String {
+  // CHARACTER and PINYIN show both variants of a word, in the order the user asked for
+  tmpls_yaml
+      .replace("CHARACTER",
+               if trad_first {
+                 "{{#Traditional}}<span class=\"nobr\">{{Traditional}}</span>|{{/Traditional}}<span class=\"nobr\">{{Simplified}}</span>"
+               } else {
+                 "<span class=\"nobr\">{{Simplified}}</span>{{#Traditional}}|<span class=\"nobr\">{{Traditional}}</span>{{/Traditional}}"
+               })
+      .replace("PINYIN",
+               if trad_first {
+                 "{{#Taiwan Pinyin}}{{Taiwan Pinyin}} | {{/Taiwan Pinyin}}{{Pinyin}}"
+               } else {
+                 "{{Pinyin}}{{#Taiwan Pinyin}} | {{Taiwan Pinyin}}{{/Taiwan Pinyin}}"
+               })
+}
+
+pub fn word_model(title: &str, trad_first: bool) -> collection::Model {
+  // fields are Simplified, Traditional, Pinyin, English, Classifier, Taiwan Pinyin, Words With Same
+  // Pinyin, Audio, Stroke Order, Also Written, Also Pronounced; cards are English, Simplified,
+  // Traditional, Pinyin
+  let mut model = collection::Model::new(
+      anki::MODEL_ID, title, anki::DECK_ID,
+      collection::fields_from_json(include_str!("flds.json")),
+      collection::templates_from_yaml(
+          &expand_placeholders(include_str!("templates.yaml"), trad_first)),
+      include_str!("card.css"));
+  model.req = vec!(
+      collection::CardRequirement{ord: 0, kind: "all".to_string(), fields: vec!(2)},
+      collection::CardRequirement{ord: 1, kind: "all".to_string(), fields: vec!(0)},
+      collection::CardRequirement{ord: 2, kind: "all".to_string(), fields: vec!(1)},
+      collection::CardRequirement{ord: 3, kind: "all".to_string(), fields: vec!(3)});
+  model
+}
+
+pub fn character_model(title: &str, trad_first: bool) -> collection::Model {
+  // fields are Simplified, Traditional, Pinyin, Taiwan Pinyin, English, Words (the words in the
+  // deck that contain the character); there's a single recognition card
+  let mut model = collection::Model::new(
+      CHARACTER_MODEL_ID, &(title.to_string() + " Character"), anki::DECK_ID,
+      vec!(
+          collection::Field::new("Simplified", 0, 20),
+          collection::Field::new("Traditional", 1, 20),
+          collection::Field::new("Pinyin", 2, 12),
+          collection::Field::new("Taiwan Pinyin", 3, 20),
+          collection::Field::new("English", 4, 12),
+          collection::Field::new("Words", 5, 20)),
+      collection::templates_from_yaml(
+          &expand_placeholders(include_str!("character_templates.yaml"), trad_first)),
+      include_str!("card.css"));
+  model.req = vec!(
+      collection::CardRequirement{ord: 0, kind: "all".to_string(), fields: vec!(0)});
+  model
+}
+
+pub fn cloze_model(title: &str) -> collection::Model {
+  // fields are Text (using Anki's {{c1::...}} syntax) and Translation; each cloze number in Text
+  // gets its own card
+  let mut model = collection::Model::new(
+      CLOZE_MODEL_ID, &(title.to_string() + " Sentence"), anki::DECK_ID,
+      vec!(
+          collection::Field::new("Text", 0, 20),
+          collection::Field::new("Translation", 1, 12)),
+      collection::templates_from_yaml(include_str!("cloze_templates.yaml")),
+      include_str!("card.css"));
+  model.kind = collection::ModelKind::Cloze;
+  model.req = vec!(
+      collection::CardRequirement{ord: 0, kind: "any".to_string(), fields: vec!(0)});
+  model
+}
+
+pub fn cloze_ords(text: &str) -> Vec<i64> {
+  // returns the card ords that a cloze note with the given Text field has, i.e. c1 -> 0, c2 -> 1
+  let cloze_re = regex!(r"\{\{c(\d+)::");
+  let mut rv : Vec<i64> = cloze_re.captures_iter(text)
+      .filter_map(|cap| cap.at(1).unwrap().parse::<i64>().ok())
+      .filter(|&n| n > 0)
+      .map(|n| n - 1)
+      .collect();
+  rv.sort();
+  rv.dedup();
+  rv
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cloze_ords() {
+    assert_eq!(cloze_ords("我{{c2::喜欢}}{{c1::喝}}{{c2::茶}}。"), vec!(0, 1));
+    assert_eq!(cloze_ords("no clozes"), Vec::<i64>::new());
+  }
+}
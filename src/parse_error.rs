@@ -0,0 +1,96 @@
+use std;
+
+// Problems found while reading the dictionary, word lists and preferred entries. None of them stop
+// the build on their own: the offending line is skipped (or the word left out) and the problem is
+// returned alongside whatever could be read, so that main can report it, or refuse to go on when
+// --strict is given.
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ErrorKind {
+  // a line of a CC-CEDICT file (the dictionary or an extra entries file) that isn't an entry
+  MalformedEntry(String),
+  // a classifier in a CL: definition that couldn't be parsed; the rest of the entry is kept
+  MalformedClassifier(String),
+  // a line of a word list that couldn't be parsed
+  MalformedWordListLine(String),
+  // a word in a word list that isn't in the dictionary
+  NotInDict(String),
+  // a preferred_entries.yaml document or entry that isn't shaped as expected
+  MalformedPreferredEntry(String),
+  // found by the lint subcommand: a preferred_entries.yaml key that no word list looks up
+  UnusedPreferredEntry(String),
+  // found by the lint subcommand: a preferred entry that no dictionary entry matches
+  UnsatisfiablePreferredEntry(String),
+  // found by the lint subcommand: preferred entries for a word that pick the same dictionary entry
+  ConflictingPreferredEntry(String),
+  // found by the lint subcommand: an extra dictionary entry with the same headword and pinyin as a
+  // CC-CEDICT one, so that the word has both
+  ShadowingEntry(String),
+  // a line of a --sentences file without any {{c1::...}} deletions
+  MalformedSentence(String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+  // the path of the file the problem was found in, e.g. "src/hsk_wordlist.csv"
+  pub file: String,
+  // 1-based; None for problems that can't be pinned to a line
+  pub line: Option<usize>,
+  pub kind: ErrorKind,
+}
+
+impl ParseError {
+  pub fn new(file: &str, line: Option<usize>, kind: ErrorKind) -> ParseError {
+    ParseError { file: file.to_string(), line: line, kind: kind }
+  }
+
+  pub fn message(&self) -> String {
+    // what's wrong, without the file and line
+    match self.kind {
+      ErrorKind::MalformedEntry(ref line) => format!("not a CC-CEDICT entry: {}", line),
+      ErrorKind::MalformedClassifier(ref clfr) => {
+        format!("couldn't parse {} as a classifier", clfr)
+      },
+      ErrorKind::MalformedWordListLine(ref line) => {
+        format!("couldn't parse word list line: {}", line)
+      },
+      ErrorKind::NotInDict(ref word) => format!("{} not in dict", word),
+      ErrorKind::MalformedPreferredEntry(ref msg) => msg.clone(),
+      ErrorKind::UnusedPreferredEntry(ref key) => format!("{} isn't on any word list", key),
+      ErrorKind::UnsatisfiablePreferredEntry(ref msg) => msg.clone(),
+      ErrorKind::ConflictingPreferredEntry(ref msg) => msg.clone(),
+      ErrorKind::ShadowingEntry(ref entry) => {
+        format!("{} has the same headword and pinyin as a CC-CEDICT entry", entry)
+      },
+      ErrorKind::MalformedSentence(ref line) => {
+        format!("sentence has no {{{{c1::...}}}} deletions: {}", line)
+      },
+    }
+  }
+}
+
+impl std::fmt::Display for ParseError {
+  fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+    try!(write!(formatter, "{}", self.file));
+    match self.line {
+      Some(line) => try!(write!(formatter, ":{}", line)),
+      None => (),
+    }
+    write!(formatter, ": {}", self.message())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_display() {
+    let err = ParseError::new("src/extra_dict.txt", Some(3), ErrorKind::NotInDict("你".to_string()));
+    assert_eq!(err.to_string(), "src/extra_dict.txt:3: 你 not in dict");
+    let err = ParseError::new(
+        "src/preferred_entries.yaml", None,
+        ErrorKind::MalformedPreferredEntry("data wasn't a mapping".to_string()));
+    assert_eq!(err.to_string(), "src/preferred_entries.yaml: data wasn't a mapping");
+  }
+}
@@ -1,52 +1,146 @@
 extern crate yaml;
 use cedict;
+use chinese_note::{EntryChoice, Rule};
+use parse_error::{ErrorKind, ParseError};
 use std::collections::HashMap;
 use yaml::constructor::*;
 
 #[derive(Clone)]
 struct PreferredEntry {
+  // the entry is the first one that matches all of pinyin, trad and def that aren't empty
   pinyin: String,
   trad: String,
+  // part of one of the entry's definitions
+  def: String,
+  // if given, used in place of the entry's definitions
+  defs: Option<Vec<String>>,
+  // if given, only this many of the entry's definitions are kept
+  max_defs: Option<usize>,
+}
+
+impl PreferredEntry {
+  fn selects(&self, entry: &cedict::Entry) -> bool {
+    (self.pinyin == "" || self.pinyin == entry.pinyin)
+        && (self.trad == "" || self.trad == entry.trad)
+        && (self.def == "" || entry.defs.iter().any(|d| d.contains(&self.def as &str)))
+  }
+
+  fn describe(&self) -> String {
+    // e.g. "pinyin xing2, def walk"
+    let mut rv = Vec::new();
+    for &(name, val) in &[("pinyin", &self.pinyin), ("trad", &self.trad), ("def", &self.def)] {
+      if *val != "" {
+        rv.push(format!("{} {}", name, val));
+      }
+    }
+    if rv.is_empty() { "anything".to_string() } else { rv.connect(", ") }
+  }
+
+  fn rewrite(&self, entry: &cedict::Entry) -> cedict::Entry {
+    let mut rv = entry.clone();
+    match self.defs {
+      Some(ref defs) => { rv.defs = defs.clone(); },
+      None => (),
+    }
+    match self.max_defs {
+      Some(n) => { rv.defs.truncate(n); },
+      None => (),
+    }
+    rv
+  }
 }
 
 fn is_good(entry: &cedict::Entry) -> bool {
   let reference_re = regex!(r"^variant of |old variant of |^see [^ ]+\[[^\]]+\]$");
-  if reference_re.is_match(entry.defs[0]) {
+  if reference_re.is_match(&entry.defs[0]) {
     return false;
   }
   let firstchar = entry.pinyin.chars().next().unwrap();
   !('A' <= firstchar && firstchar <= 'Z')
 }
 
-fn yaml_to_preferred_entry(y: YamlStandardData) -> PreferredEntry {
+const PREFERRED_ENTRIES_PATH : &'static str = "src/preferred_entries.yaml";
+
+fn yaml_string(y: YamlStandardData) -> Result<String, String> {
+  match y {
+    YamlStandardData::YamlString(s) => Ok(s),
+    _ => Err("data wasn't a string".to_string()),
+  }
+}
+
+fn yaml_to_preferred_entry(y: YamlStandardData) -> Result<PreferredEntry, String> {
   let mapping = match y {
     YamlStandardData::YamlMapping(m) => m,
-    _ => panic!("data wasn't a mapping"),
+    _ => { return Err("data wasn't a mapping".to_string()); },
   };
-  let mut rv = PreferredEntry{pinyin: "".to_string(), trad: "".to_string()};
+  let mut rv = PreferredEntry{
+      pinyin: "".to_string(), trad: "".to_string(), def: "".to_string(), defs: None,
+      max_defs: None};
   for (key, val) in mapping {
-    let key_str = match key {
-      YamlStandardData::YamlString(s) => s,
-      _ => panic!("data wasn't a string"),
-    };
-    let val_str = match val {
-      YamlStandardData::YamlString(s) => s,
-      _ => panic!("data wasn't a string"),
-    };
+    let key_str = try!(yaml_string(key));
     if key_str == "pinyin" {
-      rv.pinyin = val_str;
+      rv.pinyin = try!(yaml_string(val));
     } else if key_str == "trad" {
-      rv.trad = val_str;
+      rv.trad = try!(yaml_string(val));
+    } else if key_str == "def" {
+      rv.def = try!(yaml_string(val));
+    } else if key_str == "defs" {
+      let items = match val {
+        YamlStandardData::YamlSequence(items) => items,
+        _ => { return Err("defs wasn't a list".to_string()); },
+      };
+      let mut defs = Vec::new();
+      for item in items {
+        defs.push(try!(yaml_string(item)));
+      }
+      if defs.is_empty() {
+        return Err("defs was empty".to_string());
+      }
+      rv.defs = Some(defs);
+    } else if key_str == "max_defs" {
+      match val {
+        YamlStandardData::YamlInteger(n) if n > 0 => { rv.max_defs = Some(n as usize); },
+        _ => { return Err("max_defs wasn't a positive integer".to_string()); },
+      }
+    } else {
+      return Err(format!("unknown key {}", key_str));
     }
   }
-  rv
+  Ok(rv)
 }
 
-fn get_preferred_entry_map() -> HashMap<String, PreferredEntry> {
+fn yaml_to_preferred_entries(y: YamlStandardData) -> Result<Vec<PreferredEntry>, String> {
+  // either one entry, or a list of entries to be shown together on the word's note
+  match y {
+    YamlStandardData::YamlSequence(items) => {
+      if items.is_empty() {
+        return Err("list of entries was empty".to_string());
+      }
+      let mut rv = Vec::new();
+      for item in items {
+        rv.push(try!(yaml_to_preferred_entry(item)));
+      }
+      Ok(rv)
+    },
+    y => Ok(vec!(try!(yaml_to_preferred_entry(y)))),
+  }
+}
+
+fn read_preferred_entries(in_str: &str, path: &str,
+                          rv: &mut HashMap<String, Vec<PreferredEntry>>,
+                          errors: &mut Vec<ParseError>) {
+  // adds the words in in_str (the contents of path) to rv, replacing any that are already there
   // TODO: the way this function works is sorta janky, try to make it cleaner
-  let mut rv = HashMap::new();
-  let in_str = include_str!("preferred_entries.yaml");
-  let yaml_docs = yaml::parse_bytes_utf8(in_str.as_bytes()).unwrap();
+  let error = |msg: String| {
+    ParseError::new(path, None, ErrorKind::MalformedPreferredEntry(msg))
+  };
+  let yaml_docs = match yaml::parse_bytes_utf8(in_str.as_bytes()) {
+    Ok(docs) => docs,
+    Err(e) => {
+      errors.push(error(format!("couldn't parse YAML: {:?}", e)));
+      return;
+    },
+  };
   // There's only one doc, but we want to get an owned copy of it, so we can't
   // do yaml_docs[0]. Instead, we do a for-loop over yaml_docs. This moves it
   // into the for-loop, so it gets destroyed as we go along
@@ -57,56 +151,181 @@ fn get_preferred_entry_map() -> HashMap<String, PreferredEntry> {
 
   let yaml_vec = match yaml_doc {
     YamlStandardData::YamlMapping(v) => v,
-    _ => panic!("data wasn't a mapping"),
+    // an empty file
+    YamlStandardData::YamlNull => Vec::new(),
+    _ => {
+      errors.push(error("data wasn't a mapping".to_string()));
+      return;
+    },
   };
 
   for (key, val) in yaml_vec {
-    let key_str = match key {
-      YamlStandardData::YamlString(s) => s,
-      _ => panic!("data wasn't a string"),
+    let key_str = match yaml_string(key) {
+      Ok(key_str) => key_str,
+      Err(e) => {
+        errors.push(error(e));
+        continue;
+      },
     };
-    rv.insert(key_str, yaml_to_preferred_entry(val));
+    match yaml_to_preferred_entries(val) {
+      Ok(entries) => { rv.insert(key_str, entries); },
+      Err(e) => { errors.push(error(format!("{}: {}", key_str, e))); },
+    }
+  }
+}
+
+fn get_preferred_entry_map(overrides: &Vec<(String, String)>)
+    -> (HashMap<String, Vec<PreferredEntry>>, Vec<ParseError>) {
+  // overrides are the paths and contents of --preferred_entries files, each of which takes
+  // precedence over preferred_entries.yaml and the files before it
+  let mut rv = HashMap::new();
+  let mut errors = Vec::new();
+  read_preferred_entries(
+      include_str!("preferred_entries.yaml"), PREFERRED_ENTRIES_PATH, &mut rv, &mut errors);
+  for &(ref path, ref contents) in overrides {
+    read_preferred_entries(contents, path, &mut rv, &mut errors);
+  }
+  (rv, errors)
+}
+
+fn key_line(in_str: &str, key: &str) -> Option<usize> {
+  // the line of in_str that the top-level key is on, assuming that it's written as a plain or
+  // quoted scalar (without escapes); its value may follow on the same line, e.g. in flow style
+  let forms = vec!(key.to_string(), format!("\"{}\"", key), format!("'{}'", key));
+  in_str.lines().position(|line| {
+    forms.iter().any(|form| {
+      line.starts_with(form as &str) && {
+        let rest = &line[form.len()..];
+        rest.starts_with(":") && (rest.len() == 1 || rest[1..].starts_with(char::is_whitespace))
+      }
+    })
+  }).map(|i| i + 1)
+}
+
+pub fn lint(dict: &cedict::Dict, keys: &Vec<String>, overrides: &Vec<(String, String)>)
+    -> Vec<ParseError> {
+  // returns the problems with preferred_entries.yaml and overrides (the paths and contents of
+  // --preferred_entries files), each checked on its own: parse errors, words that aren't among
+  // keys (see hsk::preferred_entry_keys), and entries that don't pick any of dict's entries or
+  // pick the same one as another entry for the word
+  let mut errors =
+      lint_file(include_str!("preferred_entries.yaml"), PREFERRED_ENTRIES_PATH, dict, keys);
+  for &(ref path, ref contents) in overrides {
+    errors.extend(lint_file(contents, path, dict, keys).into_iter());
+  }
+  errors
+}
+
+fn lint_file(in_str: &str, path: &str, dict: &cedict::Dict, keys: &Vec<String>)
+    -> Vec<ParseError> {
+  // in_str is the contents of the preferred entries file at path
+  let mut map = HashMap::new();
+  let mut errors = Vec::new();
+  read_preferred_entries(in_str, path, &mut map, &mut errors);
+  // in the order they're in the file, followed by any whose line couldn't be found
+  let mut words : Vec<(bool, Option<usize>, &String)> = map.keys().map(|word| {
+    let line = key_line(in_str, word);
+    (line.is_none(), line, word)
+  }).collect();
+  words.sort();
+  for (_, line, word) in words {
+    let error = |kind| ParseError::new(path, line, kind);
+    if !keys.contains(word) {
+      errors.push(error(ErrorKind::UnusedPreferredEntry(word.clone())));
+    }
+    // the word without its part of speech
+    let simp = word.split(' ').next().unwrap();
+    let entries = dict.search_simp(simp);
+    let mut picked : Vec<&cedict::Entry> = Vec::new();
+    for p in map.get(word).unwrap() {
+      match entries.iter().find(|entry| p.selects(entry)) {
+        Some(entry) => {
+          if picked.contains(&entry) {
+            errors.push(error(ErrorKind::ConflictingPreferredEntry(format!(
+                "{}: {} picks {}, which another entry for the word already picks",
+                word, p.describe(), cedict::format_entry(entry)))));
+          } else {
+            picked.push(entry);
+          }
+        },
+        None => {
+          errors.push(error(ErrorKind::UnsatisfiablePreferredEntry(format!(
+              "{}: no entry matches {}", word, p.describe()))));
+        },
+      }
+    }
   }
+  errors
+}
 
+fn pick_preferred(ps: &Vec<PreferredEntry>, entries: &Vec<cedict::Entry>) -> Vec<cedict::Entry> {
+  // the entries that ps pick, rewritten as they say, leaving out the ones that don't match anything
+  let mut picked : Vec<&cedict::Entry> = Vec::new();
+  let mut rv = Vec::new();
+  for p in ps {
+    match entries.iter().find(|entry| p.selects(entry)) {
+      Some(entry) => {
+        if !picked.contains(&entry) {
+          picked.push(entry);
+          rv.push(p.rewrite(entry));
+        }
+      },
+      None => (),
+    }
+  }
   rv
 }
 
 
-fn best_entry<'a>(simp: &str,
-                  part_of_speech: Option<&str>,
-                  dict: &cedict::Dict<'a>,
-                  preferred: &HashMap<String, PreferredEntry>)
-                  -> cedict::Entry<'a> {
-  let entries = dict.search_simp(&simp);
+fn pick_entries(simp: &str,
+                part_of_speech: Option<&str>,
+                pinyin: Option<&str>,
+                dict: &cedict::Dict,
+                preferred: &HashMap<String, Vec<PreferredEntry>>)
+                -> (Vec<cedict::Entry>, Rule) {
+  // the first entry returned is the note's main one; there are only others when
+  // preferred_entries.yaml lists several entries for the word. If pinyin is given, only entries
+  // with that reading are considered (unless there aren't any).
+  let mut entries = dict.search_simp(&simp);
+  match pinyin {
+    Some(pinyin) => {
+      // the case matters, e.g. [wan2] isn't the surname [Wan2]
+      let with_pinyin : Vec<cedict::Entry> =
+          entries.iter().filter(|e| e.pinyin == pinyin).cloned().collect();
+      if !with_pinyin.is_empty() {
+        entries = with_pinyin;
+      }
+    },
+    None => (),
+  }
   let mut matches = 0;
   let key = match part_of_speech {
     Some(s) => simp.to_string() + " " + s,
     None => simp.to_string(),
   };
+  match preferred.get(&key) {
+    Some(ps) => {
+      let rv = pick_preferred(ps, &entries);
+      if !rv.is_empty() {
+        return (rv, Rule::Preferred);
+      }
+    },
+    None => (),
+  }
   for entry in &entries {
-    match preferred.get(&key) {
-      Some(p) => {
-        if (p.pinyin == "" || p.pinyin == entry.pinyin)
-            && (p.trad == "" || p.trad == entry.trad) {
-          return entry.clone();
-        }
-      },
-      _ => ()
-    }
     if is_good(&entry) {
       matches += 1;
     }
   }
 
-  /*
-  if matches > 1 {
-     println!("multiple matches for {}", entries[0].simp);
-  } else if matches == 0 {
-    println!("no good matches for {}", entries[0].simp);
-  }
-  */
-
   let mut rv = entries[0].clone();
+  let rule = if entries.len() == 1 {
+    Rule::Only
+  } else if matches >= 1 {
+    Rule::Good
+  } else {
+    Rule::Fallback
+  };
 
   if matches >= 1 {
     for entry in &entries {
@@ -116,45 +335,171 @@ fn best_entry<'a>(simp: &str,
     }
   }
 
-  if rv.defs[0].starts_with("erhua variant of ") {
-    let mut actual_simp = "".to_string();
-    // TODO: this is terrible, just terrible. Tixif!
-    let mut prev = '\0';
-    for c in simp.chars() {
-      if prev != '\0' {
-        actual_simp.push(prev);
+  (vec!(rv), rule)
+}
+
+fn best_entries(simp: &str,
+                part_of_speech: Option<&str>,
+                dict: &cedict::Dict,
+                preferred: &HashMap<String, Vec<PreferredEntry>>)
+                -> (Vec<cedict::Entry>, Rule, Option<ErrorKind>) {
+  // like pick_entries, but an erhua word that's only a reference to another word gets the
+  // definitions of the entry picked for that word. Also returns the problem with the word, if
+  // there is one, i.e. that the word the reference points to isn't in the dictionary.
+  let (mut entries, rule) = pick_entries(simp, part_of_speech, None, dict, preferred);
+  if rule == Rule::Preferred {
+    // preferred_entries.yaml picked the entries; they're used as they are
+    return (entries, rule, None);
+  }
+  let mut rv = entries.remove(0);
+  let erhua_of = match cedict::parse_cross_ref(&rv.defs[0]) {
+    Some(ref r) if r.kind == cedict::RefKind::Erhua && r.simp != simp => Some(r.clone()),
+    _ => None,
+  };
+  let mut problem = None;
+  match erhua_of {
+    Some(r) => {
+      // erhua words take the definitions of the word they're a variant of, chosen the same way
+      // as that word's own
+      if dict.search_simp(&r.simp).is_empty() {
+        let target =
+            if r.pinyin == "" { r.simp.clone() } else { format!("{}[{}]", r.simp, r.pinyin) };
+        problem = Some(ErrorKind::NotInDict(
+            format!("{} (which {} is an erhua variant of)", target, simp)));
+      } else {
+        let ref_pinyin = if r.pinyin == "" { None } else { Some(&r.pinyin as &str) };
+        let (actual_word, _) =
+            pick_entries(&r.simp, part_of_speech, ref_pinyin, dict, preferred);
+        let actual_word = &actual_word[0];
+        rv = cedict::Entry{
+            trad: rv.trad,
+            simp: rv.simp,
+            pinyin: rv.pinyin,
+            tw_pinyin: actual_word.tw_pinyin.clone(),
+            alt_pinyin: rv.alt_pinyin,
+            defs: actual_word.defs.clone(),
+            clfrs: actual_word.clfrs.clone()};
       }
-      prev = c;
-    }
-    let actual_word = best_entry(&actual_simp, part_of_speech, dict, preferred);
-    rv = cedict::Entry{
-        trad: rv.trad,
-        simp: rv.simp,
-        pinyin: rv.pinyin,
-        tw_pinyin: actual_word.tw_pinyin.clone(),
-        defs: actual_word.defs.clone(),
-        clfrs: actual_word.clfrs.clone()};
+    },
+    None => (),
   }
 
-  rv
+  (vec!(rv), rule, problem)
 }
 
-pub struct PreferredEntryGetter<'d, 'e: 'd> {
-  map: HashMap<String, PreferredEntry>,
-  dict: &'d cedict::Dict<'e>,
+pub struct PreferredEntryGetter<'d> {
+  map: HashMap<String, Vec<PreferredEntry>>,
+  dict: &'d cedict::Dict,
 }
 
 // TODO: move code above into this impl
-impl<'d, 'e> PreferredEntryGetter<'d, 'e> {
-  pub fn new(dict: &'d cedict::Dict<'e>) -> PreferredEntryGetter<'d, 'e> {
-    PreferredEntryGetter {
-      map: get_preferred_entry_map(),
-      dict: dict,
-    }
+impl<'d> PreferredEntryGetter<'d> {
+  pub fn new(dict: &'d cedict::Dict, overrides: &Vec<(String, String)>)
+      -> (PreferredEntryGetter<'d>, Vec<ParseError>) {
+    // overrides are as for get_preferred_entry_map; also returns the problems found in
+    // preferred_entries.yaml and them, and entries with problems are ignored
+    let (map, errors) = get_preferred_entry_map(overrides);
+    (PreferredEntryGetter { map: map, dict: dict }, errors)
   }
 
   pub fn get(&self, simp: &str, part_of_speech: Option<&str>)
-      -> cedict::Entry<'e> {
-    best_entry(simp, part_of_speech, &self.dict, &self.map)
+      -> (cedict::Entry, Option<ErrorKind>) {
+    // just the main entry, and the problem with the word if there is one (see choose)
+    let (mut entries, _, problem) = best_entries(simp, part_of_speech, &self.dict, &self.map);
+    (entries.remove(0), problem)
+  }
+
+  pub fn choose(&self, simp: &str, part_of_speech: Option<&str>)
+      -> (cedict::Entry, Vec<cedict::Entry>, EntryChoice, Option<ErrorKind>) {
+    // like get, but also returns the other entries to show on the word's note, and says how the
+    // entries were chosen, and from what. The problem returned, if any, is that simp is an erhua
+    // variant of a word that isn't in the dictionary; the entry is used as it is.
+    let (mut entries, rule, problem) =
+        best_entries(simp, part_of_speech, &self.dict, &self.map);
+    let entry = entries.remove(0);
+    (entry, entries, EntryChoice { rule: rule, candidates: self.dict.search_simp(simp) }, problem)
+  }
+
+  pub fn pick(&self, simp: &str, entries: &Vec<cedict::Entry>) -> Option<Vec<cedict::Entry>> {
+    // for word lists that narrow a word down to some entries themselves (see hanping): the ones of
+    // entries that the preferred entries for simp pick, if they pick any
+    let rv = match self.map.get(simp) {
+      Some(ps) => pick_preferred(ps, entries),
+      None => Vec::new(),
+    };
+    if rv.is_empty() { None } else { Some(rv) }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cedict;
+
+  #[test]
+  fn test_overrides() {
+    let dict = cedict::Dict::new(concat!(
+        "行 行 [hang2] /row/line/profession/\n",
+        "行 行 [xing2] /to walk/to go/capable/\n",
+        "好 好 [hao3] /good/well/\n",
+        "好 好 [hao4] /to be fond of/\n"));
+    let overrides = vec!(
+        ("a.yaml".to_string(), "行:\n  def: profession\n  max_defs: 2\n".to_string()),
+        ("b.yaml".to_string(), "好:\n  pinyin: hao3\n  defs: [fine]\n".to_string()),
+        ("c.yaml".to_string(), "好:\n  pinyin: hao3\n  color: red\n".to_string()));
+    let (preferred, errors) = PreferredEntryGetter::new(&dict, &overrides);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "c.yaml: 好: unknown key color");
+    assert_eq!(cedict::format_entry(&preferred.get("行", None).0), "行 行 [hang2] /row/line/");
+    assert_eq!(cedict::format_entry(&preferred.get("好", None).0), "好 好 [hao3] /fine/");
+    let entries = dict.search_simp("好");
+    assert_eq!(preferred.pick("好", &entries).unwrap()[0].defs, vec!("fine"));
+    assert_eq!(preferred.pick("了", &entries), None);
+  }
+
+  #[test]
+  fn test_erhua() {
+    let dict = cedict::Dict::new(concat!(
+        "玩兒 玩儿 [wan2 r5] /erhua variant of 玩[wan2]/\n",
+        "玩 玩 [Wan2] /surname Wan/\n",
+        "玩 玩 [wan2] /to play/to have fun/\n",
+        "玩 玩 [wan4] /to enjoy/\n",
+        "事兒 事儿 [shi4 r5] /erhua variant of 事[shi4]/\n"));
+    let overrides = vec!(("a.yaml".to_string(), "玩:\n  max_defs: 1\n".to_string()));
+    let (preferred, _) = PreferredEntryGetter::new(&dict, &overrides);
+    // the definitions come from the entry that the preferred entries pick for 玩 with the reading
+    // the reference gives
+    let (entry, problem) = preferred.get("玩儿", None);
+    assert_eq!(cedict::format_entry(&entry), "玩兒 玩儿 [wan2 r5] /to play/");
+    assert_eq!(problem, None);
+    let (entry, problem) = preferred.get("事儿", None);
+    assert_eq!(entry.defs, vec!("erhua variant of 事[shi4]"));
+    assert_eq!(problem, Some(ErrorKind::NotInDict(
+        "事[shi4] (which 事儿 is an erhua variant of)".to_string())));
+  }
+
+  #[test]
+  fn test_key_line() {
+    let in_str = "# comment\n行:\n  pinyin: xing2\n长 动词:\n  pinyin: zhang3\n";
+    assert_eq!(key_line(in_str, "行"), Some(2));
+    assert_eq!(key_line(in_str, "长 动词"), Some(4));
+    assert_eq!(key_line(in_str, "长"), None);
+    let in_str = "\"长 动词\":\n  pinyin: zhang3\n'行': {pinyin: xing2}\n长:x\n";
+    assert_eq!(key_line(in_str, "长 动词"), Some(1));
+    assert_eq!(key_line(in_str, "行"), Some(3));
+    assert_eq!(key_line(in_str, "长"), None);
+  }
+
+  #[test]
+  fn test_lint() {
+    let dict = cedict::Dict::new(concat!(
+        "行 行 [hang2] /row/\n",
+        "行 行 [xing2] /to walk/\n"));
+    // override files are checked too, with their own paths and lines
+    let overrides = vec!(("a.yaml".to_string(), "# 行\n'行': {pinyin: xing4}\n".to_string()));
+    let problems = lint(&dict, &vec!("行".to_string()), &overrides);
+    assert!(problems.contains(&ParseError::new(
+        "a.yaml", Some(2),
+        ErrorKind::UnsatisfiablePreferredEntry("行: no entry matches pinyin xing4".to_string()))));
   }
 }
@@ -0,0 +1,126 @@
+use std;
+
+// Just enough of a protocol buffers encoder to produce the config blobs that newer versions of Anki
+// store in the notetypes, fields, templates, decks and deck_config tables.
+
+const WIRE_VARINT : u64 = 0;
+const WIRE_FIXED32 : u64 = 5;
+const WIRE_LENGTH_DELIMITED : u64 = 2;
+
+pub struct Message {
+  buf: Vec<u8>,
+}
+
+fn push_varint(buf: &mut Vec<u8>, mut val: u64) {
+  while val >= 0x80 {
+    buf.push((val & 0x7f) as u8 | 0x80);
+    val >>= 7;
+  }
+  buf.push(val as u8);
+}
+
+impl Message {
+  pub fn new() -> Message {
+    Message { buf: Vec::new() }
+  }
+
+  fn key(&mut self, field: u64, wire_type: u64) {
+    push_varint(&mut self.buf, (field << 3) | wire_type);
+  }
+
+  // Fields that are equal to their default value are omitted, the same way proto3 encoders do it.
+
+  pub fn uint(&mut self, field: u64, val: u64) -> &mut Message {
+    if val != 0 {
+      self.key(field, WIRE_VARINT);
+      push_varint(&mut self.buf, val);
+    }
+    self
+  }
+
+  pub fn int(&mut self, field: u64, val: i64) -> &mut Message {
+    // int32/int64 fields encode negative numbers as their two's complement
+    self.uint(field, val as u64)
+  }
+
+  pub fn bool(&mut self, field: u64, val: bool) -> &mut Message {
+    self.uint(field, if val { 1 } else { 0 })
+  }
+
+  pub fn float(&mut self, field: u64, val: f32) -> &mut Message {
+    if val != 0.0 {
+      self.key(field, WIRE_FIXED32);
+      let bits : u32 = unsafe { std::mem::transmute(val) };
+      for i in 0..4 {
+        self.buf.push((bits >> (8 * i)) as u8);
+      }
+    }
+    self
+  }
+
+  pub fn floats(&mut self, field: u64, vals: &[f32]) -> &mut Message {
+    // repeated scalar fields are packed
+    if vals.len() > 0 {
+      let mut packed = Vec::new();
+      for &val in vals {
+        let bits : u32 = unsafe { std::mem::transmute(val) };
+        for i in 0..4 {
+          packed.push((bits >> (8 * i)) as u8);
+        }
+      }
+      self.raw(field, &packed);
+    }
+    self
+  }
+
+  pub fn string(&mut self, field: u64, val: &str) -> &mut Message {
+    if val != "" {
+      self.raw(field, val.as_bytes());
+    }
+    self
+  }
+
+  pub fn message(&mut self, field: u64, val: &Message) -> &mut Message {
+    // unlike scalars, an empty sub-message is still written, since its presence can be meaningful
+    // (e.g. which member of a oneof is set)
+    self.raw(field, &val.buf);
+    self
+  }
+
+  fn raw(&mut self, field: u64, bytes: &[u8]) {
+    self.key(field, WIRE_LENGTH_DELIMITED);
+    push_varint(&mut self.buf, bytes.len() as u64);
+    self.buf.extend(bytes.iter().cloned());
+  }
+
+  pub fn bytes(&self) -> Vec<u8> {
+    self.buf.clone()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_varint() {
+    assert_eq!(Message::new().uint(1, 150).bytes(), vec!(0x08, 0x96, 0x01));
+    assert_eq!(Message::new().uint(1, 0).bytes(), vec!());
+  }
+
+  #[test]
+  fn test_string_and_message() {
+    assert_eq!(Message::new().string(2, "testing").bytes(),
+               vec!(0x12, 0x07, 0x74, 0x65, 0x73, 0x74, 0x69, 0x6e, 0x67));
+    let mut inner = Message::new();
+    inner.uint(1, 1);
+    assert_eq!(Message::new().message(1, &inner).bytes(), vec!(0x0a, 0x02, 0x08, 0x01));
+  }
+
+  #[test]
+  fn test_floats() {
+    assert_eq!(Message::new().float(11, 2.5).bytes(), vec!(0x5d, 0x00, 0x00, 0x20, 0x40));
+    assert_eq!(Message::new().floats(1, &[1.0, 10.0]).bytes(),
+               vec!(0x0a, 0x08, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x20, 0x41));
+  }
+}
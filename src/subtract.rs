@@ -0,0 +1,257 @@
+extern crate getopts;
+
+use anki;
+use note_match;
+use std::collections::HashMap;
+use std::path::Path;
+
+// The subtract subcommand: combines the notes of two decks, e.g. to drop the words a learner
+// already studies elsewhere from an old deck ("new only"), or to keep just the ones they have in
+// common ("review only").
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operation {
+  // the notes of A that aren't in B
+  Difference,
+  // the notes of A that are also in B
+  Intersection,
+  // the notes of A, plus those of B that aren't in A
+  Union,
+}
+
+impl Operation {
+  fn from_str(s: &str) -> Option<Operation> {
+    match s {
+      "difference" => Some(Operation::Difference),
+      "intersection" => Some(Operation::Intersection),
+      "union" => Some(Operation::Union),
+      _ => None,
+    }
+  }
+}
+
+fn print_usage(program: &str, opts: getopts::Options) {
+  let brief = format!(concat!(
+      "Usage: {} subtract [options] A B OUT\n\n",
+      "Writes to OUT the notes of the deck in A that don't appear in the deck in B (or,\n",
+      "with --operation, the ones that do, or both decks' notes), along with their cards.\n",
+      "The deck names, options, media, etc. are taken from A. A and B are .apkg packages\n",
+      "(or bare collection files)."), program);
+  print!("{}", opts.usage(&brief));
+}
+
+fn combine(a: &mut anki::AnkiPackage, b: &anki::AnkiPackage, match_by: &note_match::MatchBy,
+           operation: Operation) -> Result<(usize, usize), String> {
+  // applies operation to a's notes in place; returns how many notes were removed from a and how
+  // many were copied into it from b
+  let a_models : HashMap<i64, anki::ModelSummary> =
+      a.models().into_iter().map(|m| (m.id, m)).collect();
+  let b_models : HashMap<i64, anki::ModelSummary> =
+      b.models().into_iter().map(|m| (m.id, m)).collect();
+  let a_index = note_match::NoteIndex::new(a, match_by);
+  let b_index = note_match::NoteIndex::new(b, match_by);
+
+  let mut removed = 0;
+  if operation != Operation::Union {
+    for note in a.notes() {
+      let in_b = !b_index.find(
+          &note_match::note_key(match_by, &note, a_models.get(&note.mid))).is_empty();
+      if in_b == (operation == Operation::Difference) {
+        a.remove_note(note.id);
+        removed += 1;
+      }
+    }
+  }
+  let mut added = 0;
+  if operation == Operation::Union {
+    for note in b.notes() {
+      let key = note_match::note_key(match_by, &note, b_models.get(&note.mid));
+      if a_index.find(&key).is_empty() {
+        try!(a.copy_note(b, &note));
+        added += 1;
+      }
+    }
+  }
+  Ok((removed, added))
+}
+
+pub fn main(program: &str, args: &[String]) {
+  let mut opts = getopts::Options::new();
+  opts.optopt(
+      "", "match",
+      concat!("How notes in A are matched to notes in B: headword (the default; the Simplified ",
+              "field, or the sort field of note types without one), guid, identity (like ",
+              "headword, but Traditional and Pinyin must match too) or field:NAME (the field ",
+              "called NAME)."),
+      "MATCH");
+  opts.optopt(
+      "", "operation",
+      concat!("Write the difference (the default; notes of A not in B), intersection (notes of A ",
+              "that are also in B) or union (notes of A, plus those of B not in A) of the two ",
+              "decks."),
+      "OPERATION");
+  opts.optflag("h", "help", "Print this help menu");
+
+  let parsed_opts = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => { panic!(f.to_string()) },
+  };
+  if parsed_opts.opt_present("h") || parsed_opts.free.len() != 3 {
+    print_usage(program, opts);
+    return;
+  }
+  let (a_path, b_path, out_path) =
+      (&parsed_opts.free[0], &parsed_opts.free[1], &parsed_opts.free[2]);
+  let match_by = match parsed_opts.opt_str("match") {
+    Some(m) => match note_match::MatchBy::from_str(&m) {
+      Some(match_by) => match_by,
+      None => {
+        panic!("Unknown --match {}; expected guid, headword, identity or field:NAME", m);
+      },
+    },
+    None => note_match::MatchBy::Headword,
+  };
+  let operation = match parsed_opts.opt_str("operation") {
+    Some(op) => match Operation::from_str(&op) {
+      Some(operation) => operation,
+      None => {
+        panic!("Unknown --operation {}; expected difference, intersection or union", op);
+      },
+    },
+    None => Operation::Difference,
+  };
+
+  match subtract(a_path, b_path, out_path, &match_by, operation) {
+    Ok((removed, added)) => {
+      println!("Removed {} notes from {} and added {} from {}", removed, a_path, added, b_path);
+    },
+    Err(e) => { panic!("{}", e); },
+  }
+}
+
+fn subtract(a_path: &str, b_path: &str, out_path: &str, match_by: &note_match::MatchBy,
+            operation: Operation) -> Result<(usize, usize), String> {
+  // combines the decks in a_path and b_path into out_path; see combine. The working copies of both
+  // collections are removed whether or not that works.
+  let mut a = match anki::AnkiPackage::open(Path::new(a_path), Path::new(out_path)) {
+    Ok(apkg) => apkg,
+    Err(e) => { return Err(format!("Could not open {}: {}", a_path, e)); },
+  };
+  // B is only read from, so its working copy goes next to OUT under a different name
+  let combined = match anki::AnkiPackage::open_read_only(
+      Path::new(b_path), Path::new(&(out_path.to_string() + ".b"))) {
+    Ok(b) => {
+      let combined = combine(&mut a, &b, match_by, operation)
+          .map_err(|e| format!("Could not copy notes from {}: {}", b_path, e));
+      let discarded = b.discard()
+          .map_err(|e| format!("Could not clean up after reading {}: {}", b_path, e));
+      combined.and_then(|counts| discarded.map(|_| counts))
+    },
+    Err(e) => Err(format!("Could not open {}: {}", b_path, e)),
+  };
+
+  match combined {
+    Ok(counts) => {
+      a.write().map(|_| counts).map_err(|e| format!("Could not write {}: {}", out_path, e))
+    },
+    Err(e) => {
+      match a.discard() {
+        Ok(_) => (),
+        Err(discard_e) => {
+          println!("Warning: could not clean up after reading {}: {}", a_path, discard_e);
+        },
+      }
+      Err(e)
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anki;
+  use anki::tests::{fields, scratch_path};
+  use deck_config;
+  use note_types;
+  use std;
+  use std::path::Path;
+
+  fn build(path: &Path, name: &str, notes: &[(&str, &str)]) {
+    // writes a deck called name with one card for each of notes, given as (GUID, Simplified)
+    let model = note_types::word_model("HSK", false);
+    let mut apkg = anki::AnkiPackage::new(
+        path, anki::Format::Anki21, name, &model, &deck_config::DeckConfig::new());
+    for &(guid, simp) in notes {
+      let note_id = apkg.add_note(&model, guid, " HSK_Level_1 ", &fields(simp, model.flds.len()));
+      apkg.add_card(note_id, anki::DECK_ID, 0);
+    }
+    apkg.write().unwrap();
+  }
+
+  fn combined(operation: Operation) -> (Vec<String>, Vec<(i64, String)>) {
+    // the headwords and decks of the combination of 你 好 and 好 们
+    let name = format!("subtract_{:?}", operation);
+    let a_path = scratch_path(&(name.clone() + "_a.apkg"));
+    let b_path = scratch_path(&(name.clone() + "_b.apkg"));
+    let out_path = scratch_path(&(name.clone() + "_out.apkg"));
+    let b_scratch_path = scratch_path(&(name.clone() + "_out.apkg.b"));
+    build(&a_path, "HSK", &[("a1", "你"), ("a2", "好")]);
+    build(&b_path, "Other", &[("b1", "好"), ("b2", "们")]);
+
+    let mut a = anki::AnkiPackage::open(&a_path, &out_path).unwrap();
+    let b = anki::AnkiPackage::open_read_only(&b_path, &b_scratch_path).unwrap();
+    combine(&mut a, &b, &note_match::MatchBy::Headword, operation).unwrap();
+    let mut headwords : Vec<String> = a.notes().into_iter().map(|n| n.flds[0].clone()).collect();
+    headwords.sort();
+    let mut decks : Vec<(i64, String)> = a.cards().into_iter().map(|card| {
+      a.conn.query_row(
+          "SELECT decks.id, decks.name FROM cards JOIN decks ON did = decks.id WHERE cards.id = ?",
+          &[&card.id], |row| (row.get(0), row.get(1)))
+    }).collect();
+    decks.sort();
+    b.discard().unwrap();
+    a.discard().unwrap();
+    std::fs::remove_file(&a_path).unwrap();
+    std::fs::remove_file(&b_path).unwrap();
+    (headwords, decks)
+  }
+
+  #[test]
+  fn test_cleanup() {
+    // when B can't be opened, the working copy of A is removed and nothing is written
+    let a_path = scratch_path("subtract_cleanup_a.apkg");
+    let b_path = scratch_path("subtract_cleanup_b.apkg");
+    let out_path = scratch_path("subtract_cleanup_out.apkg");
+    build(&a_path, "HSK", &[("a1", "你")]);
+    let result = subtract(a_path.to_str().unwrap(), b_path.to_str().unwrap(),
+                          out_path.to_str().unwrap(), &note_match::MatchBy::Headword,
+                          Operation::Difference);
+    assert!(result.unwrap_err().starts_with("Could not open"));
+    assert!(!scratch_path("subtract_cleanup_out.apkg.collection.anki21").exists());
+    assert!(!out_path.exists());
+    std::fs::remove_file(&a_path).unwrap();
+  }
+
+  #[test]
+  fn test_difference() {
+    let (headwords, _) = combined(Operation::Difference);
+    assert_eq!(headwords, vec!("你"));
+  }
+
+  #[test]
+  fn test_intersection() {
+    let (headwords, _) = combined(Operation::Intersection);
+    assert_eq!(headwords, vec!("好"));
+  }
+
+  #[test]
+  fn test_union() {
+    let (headwords, decks) = combined(Operation::Union);
+    assert_eq!(headwords, vec!("们", "你", "好"));
+    // B's top-level deck has the same ID as A's, so the copied card goes into a new deck, and
+    // A's deck keeps its name
+    assert_eq!(decks.len(), 3);
+    assert_eq!(decks.iter().filter(|&&(id, _)| id == anki::DECK_ID).count(), 2);
+    assert!(decks.iter().all(|&(id, ref name)| (id == anki::DECK_ID) == (name == "HSK")));
+  }
+}